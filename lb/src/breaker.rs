@@ -0,0 +1,257 @@
+//! Per-backend circuit breakers that take failing backends out of rotation for a while.
+
+use rand::Rng;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::lock;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BreakerSettings {
+    pub(crate) failure_threshold: u32,
+    pub(crate) window: Duration,
+    pub(crate) cooldown: Duration,
+    /// Consecutive successful probes that close a half-open breaker.
+    pub(crate) half_open_probes: u32,
+    /// Longest pause after a single failure, see `CircuitBreaker::paused_until`.
+    pub(crate) failure_cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BreakerState {
+    /// Requests flow normally while failures are counted.
+    Closed,
+    /// Too many recent failures: the backend is skipped until the cooldown ends.
+    Open { until: Instant },
+    /// The cooldown ended; probe requests go through one at a time. `half_open_probes`
+    /// successes in a row close the breaker, any failure re-opens it.
+    HalfOpen {
+        probe_in_flight: bool,
+        successes: u32,
+    },
+}
+
+/// Per-backend circuit breaker, so a failing server stops eating a full timeout per request.
+pub(crate) struct CircuitBreaker {
+    pub(crate) state: BreakerState,
+    /// Failures inside the current window, oldest first.
+    recent_failures: VecDeque<Instant>,
+    /// Set after each failure (with `failure_cooldown_ms`): the backend is skipped until then,
+    /// without tripping the breaker.
+    paused_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new() -> Self {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            recent_failures: VecDeque::new(),
+            paused_until: None,
+        }
+    }
+
+    pub(crate) fn allows_request(&self, now: Instant) -> bool {
+        if self.paused_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open { until } => now >= until,
+            BreakerState::HalfOpen {
+                probe_in_flight, ..
+            } => !probe_in_flight,
+        }
+    }
+
+    /// Called when a request is sent to the backend; turns an expired `Open` into the probe.
+    pub(crate) fn on_dispatch(&mut self, now: Instant) {
+        match self.state {
+            BreakerState::Open { until } if now >= until => {
+                self.state = BreakerState::HalfOpen {
+                    probe_in_flight: true,
+                    successes: 0,
+                };
+            }
+            BreakerState::HalfOpen { successes, .. } => {
+                self.state = BreakerState::HalfOpen {
+                    probe_in_flight: true,
+                    successes,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Called when the probe ended without an outcome, e.g. the client went away, so the next
+    /// request can probe instead.
+    pub(crate) fn abandon_probe(&mut self) {
+        if let BreakerState::HalfOpen {
+            probe_in_flight: true,
+            successes,
+        } = self.state
+        {
+            self.state = BreakerState::HalfOpen {
+                probe_in_flight: false,
+                successes,
+            };
+        }
+    }
+
+    /// Returns true if this closed a half-open breaker.
+    fn record_success(&mut self, settings: BreakerSettings) -> bool {
+        if let BreakerState::HalfOpen { successes, .. } = self.state {
+            if successes + 1 < settings.half_open_probes {
+                self.state = BreakerState::HalfOpen {
+                    probe_in_flight: false,
+                    successes: successes + 1,
+                };
+                return false;
+            }
+            self.state = BreakerState::Closed;
+            self.recent_failures.clear();
+            return true;
+        }
+        false
+    }
+
+    /// Returns true if this tripped the breaker open.
+    fn record_failure(&mut self, now: Instant, settings: BreakerSettings) -> bool {
+        match self.state {
+            BreakerState::HalfOpen { .. } => {
+                self.state = BreakerState::Open {
+                    until: now + settings.cooldown,
+                };
+                true
+            }
+            BreakerState::Closed => {
+                self.recent_failures.push_back(now);
+                while let Some(&oldest) = self.recent_failures.front() {
+                    if now.duration_since(oldest) > settings.window {
+                        self.recent_failures.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                if self.recent_failures.len() >= settings.failure_threshold as usize {
+                    self.state = BreakerState::Open {
+                        until: now + settings.cooldown,
+                    };
+                    self.recent_failures.clear();
+                    return true;
+                }
+                false
+            }
+            BreakerState::Open { .. } => false,
+        }
+    }
+}
+
+impl BreakerState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+/// Feeds the outcome of one forwarded request into the backend's circuit breaker.
+pub(crate) fn record_outcome(
+    breaker: &Mutex<CircuitBreaker>,
+    backend: &str,
+    success: bool,
+    settings: BreakerSettings,
+) {
+    let mut breaker = lock(breaker);
+    if success {
+        if breaker.record_success(settings) {
+            info!(backend, "circuit breaker closed");
+        }
+    } else {
+        let now = Instant::now();
+        if breaker.record_failure(now, settings) {
+            warn!(
+                backend,
+                cooldown_secs = settings.cooldown.as_secs(),
+                "circuit breaker opened"
+            );
+        } else if !settings.failure_cooldown.is_zero() {
+            // Randomized so the backend doesn't get every waiting request back at the same moment.
+            let pause = settings
+                .failure_cooldown
+                .mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+            breaker.paused_until = Some(now + pause);
+            debug!(
+                backend,
+                pause_ms = pause.as_millis() as u64,
+                "pausing backend after a failure"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(failure_cooldown_ms: u64) -> BreakerSettings {
+        BreakerSettings {
+            failure_threshold: 3,
+            window: Duration::from_secs(30),
+            cooldown: Duration::from_secs(30),
+            half_open_probes: 3,
+            failure_cooldown: Duration::from_millis(failure_cooldown_ms),
+        }
+    }
+
+    #[test]
+    fn a_failure_pauses_the_backend_briefly_without_opening_the_breaker() {
+        let breaker = Mutex::new(CircuitBreaker::new());
+        record_outcome(&breaker, "b0", false, settings(200));
+        let now = Instant::now();
+        let breaker = lock(&breaker);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert!(!breaker.allows_request(now));
+        assert!(breaker.allows_request(now + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn without_a_failure_cooldown_failures_only_count_towards_the_breaker() {
+        let breaker = Mutex::new(CircuitBreaker::new());
+        record_outcome(&breaker, "b0", false, settings(0));
+        assert!(lock(&breaker).allows_request(Instant::now()));
+        record_outcome(&breaker, "b0", false, settings(0));
+        record_outcome(&breaker, "b0", false, settings(0));
+        assert!(!lock(&breaker).allows_request(Instant::now()));
+    }
+
+    #[test]
+    fn a_half_open_breaker_closes_after_enough_probes_in_a_row() {
+        let breaker = Mutex::new(CircuitBreaker::new());
+        let half_open = |successes| BreakerState::HalfOpen {
+            probe_in_flight: false,
+            successes,
+        };
+        lock(&breaker).state = half_open(0);
+        for successes in 1..3 {
+            lock(&breaker).on_dispatch(Instant::now());
+            assert!(
+                !lock(&breaker).allows_request(Instant::now()),
+                "one probe at a time"
+            );
+            record_outcome(&breaker, "b0", true, settings(0));
+            assert_eq!(lock(&breaker).state, half_open(successes));
+        }
+        record_outcome(&breaker, "b0", true, settings(0));
+        assert_eq!(lock(&breaker).state, BreakerState::Closed);
+
+        // Any failed probe re-opens it, however many succeeded before.
+        lock(&breaker).state = half_open(2);
+        record_outcome(&breaker, "b0", false, settings(0));
+        assert!(matches!(lock(&breaker).state, BreakerState::Open { .. }));
+    }
+}
@@ -0,0 +1,1205 @@
+//! The config file: its format, defaults, `LB_*` overrides and validation.
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Method, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::runtime::{self, Runtime};
+use tokio::time::Duration;
+use tracing::warn;
+
+use crate::breaker::BreakerSettings;
+use crate::discovery::{expand_template, DiscoverySource, HOST_PLACEHOLDER};
+use crate::rate_limit::RateLimit;
+
+/// Config file read at startup when `LB_CONFIG` is not set.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+/// Pool of backends that don't name one.
+const DEFAULT_POOL: &str = "default";
+/// Most of a body `body_log` may log, so it can't fill the logs with whole payloads.
+const MAX_BODY_LOG_BYTES: usize = 4096;
+/// Polling faster than this would mostly just load the metrics endpoints.
+const MIN_POLL_INTERVAL_SECS: u64 = 1;
+
+/// Routing configuration, loaded once at startup from `config.toml` (or the
+/// file named by `LB_CONFIG`) with environment variable overrides on top.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LbConfig {
+    /// Address and port the load balancer accepts client connections on.
+    pub(crate) listen_addr: SocketAddr,
+    /// Most client connections open at once on `listen_addr`; further ones are closed right
+    /// after being accepted. 0 means unlimited.
+    pub(crate) max_connections: usize,
+    /// Threads of the tokio runtime running the load balancer; one per CPU core when unset.
+    pub(crate) worker_threads: Option<usize>,
+    /// Name given to those threads, as shown by `top -H` and in thread dumps.
+    pub(crate) thread_name: String,
+    /// If the primary backend's KV cache usage ratio is equal or above this, we spill to the
+    /// others.
+    pub(crate) capacity_threshold: f64,
+    /// Once spilling, keep spilling until the primary's ratio drops below this.
+    pub(crate) release_threshold: f64,
+    /// Shift of every backend's capacity threshold for requests with `X-Priority: <key>`, e.g.
+    /// `high = 0.15` to keep high-priority requests on the primary a little longer.
+    pub(crate) priority_threshold_offsets: BTreeMap<String, f64>,
+    /// Backends in order of preference; the first one is the primary.
+    pub(crate) backends: Vec<BackendConfig>,
+    /// Names of the backend tiers, highest priority first. With the `threshold` strategy a
+    /// pool whose backends name a tier fills its best tier before spilling to the next.
+    pub(crate) tiers: Vec<String>,
+    /// When set, backends get no requests until their first KV cache scrape comes in, and this
+    /// one takes the requests of pools left with no usable backend by that, e.g. right after
+    /// startup. Unscraped backends are taken to be idle when unset.
+    pub(crate) default_backend: Option<String>,
+    /// Where to find more backends at runtime: a JSON file, an `http(s)://` URL serving one,
+    /// or `dns-a://<name>` for one backend per A/AAAA record; off when unset.
+    pub(crate) discovery_source: Option<String>,
+    /// How often `discovery_source` is read again.
+    pub(crate) discovery_interval_secs: u64,
+    /// Settings of the backends found by a `dns-a://` source, with `{host}` standing for the
+    /// address in `name`, `base_uri` and `kv_metrics_url`.
+    pub(crate) discovery_template: Option<BackendConfig>,
+    /// How many other backends to try when the chosen one refuses the connection (or returns a
+    /// `retry_on_status`). Above 0 request bodies are buffered so they can be replayed.
+    pub(crate) max_retries: usize,
+    /// Backend response statuses (e.g. 502, 503) that are retried on another backend like a
+    /// refused connection, within `max_retries`.
+    pub(crate) retry_on_status: Vec<u16>,
+    /// Largest request body accepted; bigger ones get 413 instead of being buffered.
+    pub(crate) max_body_bytes: u64,
+    /// How long to wait for a TCP connection to a backend; exceeding it fails over like a
+    /// refused connection.
+    pub(crate) connect_timeout_secs: u64,
+    /// How long an idle pooled connection to a backend is kept open for reuse.
+    pub(crate) pool_idle_timeout_secs: u64,
+    /// Most idle connections kept per backend host; unlimited when unset.
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    /// PEM certificates that `https://` backends must chain to, e.g. an internal CA. Replaces
+    /// the public web roots rather than adding to them.
+    pub(crate) upstream_ca_path: Option<String>,
+    /// Accept any certificate from `https://` backends. Only for self-signed test setups.
+    pub(crate) upstream_tls_skip_verify: bool,
+    /// PEM certificate chain presented to `https://` backends that ask for one (mutual TLS);
+    /// together with `upstream_client_key_path`.
+    pub(crate) upstream_client_cert_path: Option<String>,
+    /// PEM private key for `upstream_client_cert_path`.
+    pub(crate) upstream_client_key_path: Option<String>,
+    /// Refuse to start when the startup self-test reaches none of the backends.
+    pub(crate) require_backend_on_startup: bool,
+    /// How long to wait for a backend's response headers before answering 504. Streamed
+    /// response bodies are not limited.
+    pub(crate) backend_timeout_secs: u64,
+    /// How long a backend may take to send its response headers before the request fails
+    /// over like a refused connection; 0 leaves only `backend_timeout_secs`.
+    pub(crate) first_byte_timeout_secs: u64,
+    /// Longest a response may take from dispatch until its body is finished; a body still
+    /// streaming then is cut off. 0 disables the limit.
+    pub(crate) response_timeout_secs: u64,
+    /// Total time a request may take to get its response headers, across the admission queue
+    /// and every failover attempt; 0 disables the budget.
+    pub(crate) request_deadline_secs: u64,
+    /// Seconds between two scrapes of the backends' metrics endpoints.
+    pub(crate) metrics_poll_interval_secs: u64,
+    /// Longest delay between scrapes of a metrics endpoint that keeps failing; the delay doubles
+    /// (with jitter) from `metrics_poll_interval_secs` up to this.
+    pub(crate) metrics_backoff_max_secs: u64,
+    /// A metrics scrape that hasn't finished after this long counts as failed.
+    pub(crate) metrics_timeout_secs: u64,
+    /// How long a scrape waits for the TCP connection to the metrics endpoint; shorter than
+    /// `metrics_timeout_secs` so an unreachable backend is told apart from a slow one.
+    pub(crate) metrics_connect_timeout_ms: u64,
+    /// For this long after a backend pushes its KV stats to the admin API, its metrics
+    /// endpoint isn't polled.
+    pub(crate) metrics_push_ttl_secs: u64,
+    /// A backend whose KV ratio hasn't been refreshed for this long is treated as full until the
+    /// next successful scrape; 0 disables the check.
+    pub(crate) staleness_secs: u64,
+    /// On SIGTERM/SIGINT, how long to wait for in-flight requests before exiting anyway.
+    pub(crate) shutdown_drain_timeout_secs: u64,
+    /// Seconds between two health probes of a backend.
+    pub(crate) health_check_interval_secs: u64,
+    /// A health probe that takes longer than this counts as failed.
+    pub(crate) health_check_timeout_secs: u64,
+    /// Consecutive failed probes after which a backend leaves the routing pool.
+    pub(crate) unhealthy_threshold: u32,
+    /// Consecutive successful probes after which an unhealthy backend rejoins the pool.
+    pub(crate) healthy_threshold: u32,
+    /// Failed requests (connection errors, timeouts, 5xx) within `breaker_window_secs` that
+    /// trip a backend's circuit breaker.
+    breaker_failure_threshold: u32,
+    /// Seconds over which failures are counted towards `breaker_failure_threshold`.
+    breaker_window_secs: u64,
+    /// How long a tripped breaker keeps the backend out of rotation before letting a probe through.
+    breaker_cooldown_secs: u64,
+    /// Consecutive successful probes a half-open breaker needs before it closes.
+    breaker_half_open_probes: u32,
+    /// After any failed request, a backend is skipped for a random half to all of this, so
+    /// requests failing at once don't all land on it again; 0 disables the pause.
+    failure_cooldown_ms: u64,
+    /// Most requests in flight at once to any one backend that they failed over to; 0 means
+    /// no limit.
+    pub(crate) max_failover_in_flight: usize,
+    /// Ramp-up period after a backend comes back online or healthy, during which its share of
+    /// traffic grows linearly from nothing; 0 disables it.
+    pub(crate) warmup_secs: u64,
+    /// Per-backend latency percentiles cover the responses of the last one to two windows.
+    pub(crate) latency_window_secs: u64,
+    /// Name of the KV cache block gauge (e.g. `nv_trt_llm_kv_cache_block_metrics`); any metric
+    /// with matching labels is accepted when unset.
+    pub(crate) kv_metrics_name: Option<String>,
+    /// `model` label of the KV cache block gauges to read from the metrics endpoints.
+    pub(crate) kv_metrics_model: String,
+    /// `version` label(s) of the KV cache block gauges to read from the metrics endpoints.
+    pub(crate) kv_metrics_version: KvMetricsVersions,
+    /// Label names the KV cache block gauges use, for deployments that don't label them the
+    /// way Triton does.
+    pub(crate) kv_metrics_labels: KvMetricsLabels,
+    /// Weight of the KV cache ratio in the pressure score.
+    pub(crate) kv_pressure_weight: f64,
+    /// Weight of each new scrape in the moving average of a backend's pressure, in (0, 1];
+    /// lower values ride out short spikes, 1 routes on the latest scrape alone.
+    pub(crate) kv_smoothing_factor: f64,
+    /// Further gauges blended into the pressure score that routing and shedding act on.
+    pub(crate) pressure_metrics: Vec<PressureMetricConfig>,
+    pub(crate) routing_strategy: RoutingStrategy,
+    /// Share of an idle backend's chance that `weighted_random` still gives a full one, from 0
+    /// (none) to 1, so a backend near capacity keeps a trickle of traffic while it is online.
+    pub(crate) weight_floor: f64,
+    /// Strategy evaluated alongside `routing_strategy` for every request, only to log and count
+    /// where the two would disagree. Traffic still follows `routing_strategy`.
+    pub(crate) shadow_strategy: Option<RoutingStrategy>,
+    /// Fixed seed for the weighted strategies, for reproducible routing.
+    pub(crate) rng_seed: Option<u64>,
+    /// PEM certificate chain; together with `tls_key_path` this switches the listener to HTTPS.
+    pub(crate) tls_cert_path: Option<String>,
+    /// PEM private key for `tls_cert_path`.
+    pub(crate) tls_key_path: Option<String>,
+    /// Route requests carrying the same `session_header` value to the same backend, so its
+    /// prefix cache stays warm for the conversation.
+    pub(crate) session_affinity: bool,
+    pub(crate) session_header: String,
+    /// Assignments unused for this long are forgotten.
+    pub(crate) session_ttl_secs: u64,
+    /// Time constant of the decaying per-client request counts behind `GET /admin/top-clients`.
+    pub(crate) top_clients_window_secs: u64,
+    /// Most sessions to remember; the least recently used one is evicted beyond this.
+    pub(crate) session_capacity: usize,
+    /// Secret expected in the `X-Admin-Token` header on `/admin/` requests; the admin
+    /// endpoints are disabled on the main port when unset.
+    pub(crate) admin_token: Option<String>,
+    /// Serve the built-in and admin endpoints on `admin_listen_addr` only, so the main port
+    /// does nothing but proxy.
+    pub(crate) admin_listener: bool,
+    pub(crate) admin_listen_addr: SocketAddr,
+    /// Path prefixes routed to specific backend pools; the longest matching prefix wins.
+    routes: Vec<RouteConfig>,
+    /// Pool serving paths that match none of `routes`.
+    default_pool: String,
+    /// JSON pointer into request bodies, e.g. `/model`, whose string value picks the pool from
+    /// `model_pools`. Bodies aren't looked into while unset.
+    pub(crate) model_pointer: Option<String>,
+    /// Pool serving each model named at `model_pointer`, ahead of `routes`.
+    model_pools: BTreeMap<String, String>,
+    /// Largest body looked into for `model_pointer`; bigger and chunked ones are routed by path.
+    pub(crate) model_peek_max_bytes: u64,
+    /// Path prefixes whose request bodies are always streamed, never buffered for retries or
+    /// `model_pointer`, e.g. `/v2/health`.
+    pub(crate) stream_paths: Vec<String>,
+    /// `Retry-After` sent with the `503` returned when no backend is available.
+    pub(crate) unavailable_retry_after_secs: u64,
+    /// Answer 503 instead of forwarding when every usable backend of the pool is shedding load,
+    /// rather than pushing the least loaded one further.
+    pub(crate) reject_when_all_over_threshold: bool,
+    /// Average pressure of a pool's usable backends above which requests are shed at random;
+    /// off when unset.
+    pub(crate) shed_high_water_mark: Option<f64>,
+    /// Share of the requests shed once that average reaches 1, growing linearly from nothing
+    /// at the mark.
+    pub(crate) shed_max_probability: f64,
+    /// How many requests may wait for a backend to drop below its threshold (with
+    /// `reject_when_all_over_threshold`) or its `max_concurrency`, instead of being rejected
+    /// right away; 0 disables the queue.
+    pub(crate) admission_queue_depth: usize,
+    /// Longest a queued request waits before it is rejected after all.
+    pub(crate) admission_max_wait_secs: u64,
+    /// Buffer 4xx/5xx responses from backends and replace their bodies with the load
+    /// balancer's own JSON error envelope.
+    pub(crate) normalize_error_bodies: bool,
+    /// Gzip responses for clients that accept it when the backend sent them uncompressed.
+    pub(crate) gzip_responses: bool,
+    /// Smallest `Content-Length` worth compressing with `gzip_responses`.
+    pub(crate) gzip_min_bytes: u64,
+    /// Log one JSON line per proxied request under the `access_log` target.
+    pub(crate) access_log: bool,
+    /// Log the start of every proxied request and response body at debug level under the
+    /// `body_log` target. Bodies may carry secrets, so this is for debugging only.
+    pub(crate) body_log: bool,
+    /// How much of each body `body_log` logs; at most `MAX_BODY_LOG_BYTES`.
+    pub(crate) body_log_max_bytes: usize,
+    /// Headers removed from requests before forwarding, on top of the hop-by-hop ones.
+    pub(crate) strip_headers: Vec<String>,
+    /// The `Host` header backends see: the client's, or the backend's own authority.
+    pub(crate) host_header: HostHeader,
+    /// Origins whose browser clients may call the load balancer, or `*` for any; CORS is off
+    /// while empty.
+    pub(crate) cors_allowed_origins: Vec<String>,
+    /// Methods and request headers allowed by preflight responses.
+    pub(crate) cors_allowed_methods: Vec<String>,
+    pub(crate) cors_allowed_headers: Vec<String>,
+    /// How long browsers may cache a preflight response.
+    pub(crate) cors_max_age_secs: u64,
+    /// Honor `X-Force-Backend: <name>` to send a request to that backend, for debugging and
+    /// canaries. Keep it off wherever clients aren't trusted.
+    pub(crate) allow_force_backend: bool,
+    /// Response header naming the backend that served the request, e.g. `X-Served-By`; not
+    /// sent when unset.
+    pub(crate) served_by_header: Option<String>,
+    /// Allow `POST /admin/backends/{name}/inject_failure`, which makes a backend look like it
+    /// is failing, to try out failover and the circuit breaker.
+    pub(crate) chaos_enabled: bool,
+    /// Sustained requests per second accepted across all clients; unlimited when unset.
+    rate_limit_rps: Option<f64>,
+    /// Requests accepted at once on top of the sustained rate; defaults to one second's worth.
+    rate_limit_burst: Option<u32>,
+    /// The same limits per client IP.
+    client_rate_limit_rps: Option<f64>,
+    client_rate_limit_burst: Option<u32>,
+}
+
+/// A gauge from the backends' metrics pages that adds to their pressure score.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PressureMetricConfig {
+    /// Metric name, e.g. `nv_inference_pending_request_count`. Matching samples are summed.
+    pub(crate) name: String,
+    /// Labels a sample must carry to count.
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+    pub(crate) weight: f64,
+    /// Value at which this metric counts as full pressure.
+    #[serde(default)]
+    pub(crate) max: Option<f64>,
+    /// Metric (with the same labels) holding the value at which this one counts as full,
+    /// e.g. `nv_gpu_memory_total_bytes` for `nv_gpu_memory_used_bytes`.
+    #[serde(default)]
+    pub(crate) max_metric: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    path_prefix: String,
+    pub(crate) pool: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BackendConfig {
+    pub(crate) name: String,
+    /// Scheme and authority to forward to; requests keep their own path.
+    pub(crate) base_uri: String,
+    /// Pool this backend serves; `DEFAULT_POOL` when unset.
+    #[serde(default)]
+    pub(crate) pool: Option<String>,
+    /// Share of the traffic this backend gets when the strategy finds several equally good
+    /// backends; defaults to 1.
+    #[serde(default)]
+    pub(crate) weight: Option<u32>,
+    /// HTTP version spoken to the backend; HTTP/1.1 when unset.
+    #[serde(default)]
+    pub(crate) protocol: Option<BackendProtocol>,
+    /// Prometheus endpoint reporting this backend's KV cache usage. Backends without one are
+    /// never scraped and are treated as always online with an empty cache.
+    #[serde(default)]
+    pub(crate) kv_metrics_url: Option<String>,
+    /// How `kv_metrics_url` reports the KV cache; Prometheus text when unset.
+    #[serde(default)]
+    pub(crate) metrics_format: Option<MetricsFormat>,
+    /// Path on `base_uri`'s host to probe with a `GET`, e.g. `/v2/health/ready`. Backends
+    /// without one are not health checked.
+    #[serde(default)]
+    pub(crate) health_path: Option<String>,
+    /// Overrides of the global thresholds, e.g. so a smaller GPU sheds load earlier.
+    #[serde(default)]
+    pub(crate) capacity_threshold: Option<f64>,
+    #[serde(default)]
+    pub(crate) release_threshold: Option<f64>,
+    /// Most requests this backend is sent at once, whatever the strategy; unlimited when unset.
+    #[serde(default)]
+    pub(crate) max_concurrency: Option<usize>,
+    /// One of `tiers`; backends without one come after every tier.
+    #[serde(default)]
+    pub(crate) tier: Option<String>,
+}
+
+impl BackendConfig {
+    pub(crate) fn pool(&self) -> &str {
+        self.pool.as_deref().unwrap_or(DEFAULT_POOL)
+    }
+}
+
+/// A backend's shedding thresholds: its own where set, the global ones otherwise.
+#[derive(Clone, Copy)]
+pub(crate) struct Thresholds {
+    pub(crate) capacity: f64,
+    pub(crate) release: f64,
+}
+
+impl Default for LbConfig {
+    fn default() -> Self {
+        LbConfig {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            admin_listener: false,
+            admin_listen_addr: SocketAddr::from(([127, 0, 0, 1], 9090)),
+            max_connections: 0,
+            worker_threads: None,
+            thread_name: "lb-worker".to_string(),
+            capacity_threshold: 0.7,
+            release_threshold: 0.6,
+            priority_threshold_offsets: BTreeMap::new(),
+            backends: vec![
+                BackendConfig {
+                    name: "h100".to_string(),
+                    base_uri: "http://192.168.1.18:8000".to_string(),
+                    pool: None,
+                    weight: None,
+                    protocol: None,
+                    kv_metrics_url: Some("http://0.0.0.0:8002/metrics".to_string()),
+                    metrics_format: None,
+                    health_path: None,
+                    capacity_threshold: None,
+                    release_threshold: None,
+                    max_concurrency: None,
+                    tier: None,
+                },
+                BackendConfig {
+                    name: "l40".to_string(),
+                    base_uri: "http://192.168.1.13:8003".to_string(),
+                    pool: None,
+                    weight: None,
+                    protocol: None,
+                    kv_metrics_url: None,
+                    metrics_format: None,
+                    health_path: None,
+                    capacity_threshold: None,
+                    release_threshold: None,
+                    max_concurrency: None,
+                    tier: None,
+                },
+            ],
+            tiers: Vec::new(),
+            default_backend: None,
+            discovery_source: None,
+            discovery_interval_secs: 30,
+            discovery_template: None,
+            max_retries: 0,
+            retry_on_status: Vec::new(),
+            max_body_bytes: 16 * 1024 * 1024,
+            connect_timeout_secs: 5,
+            pool_idle_timeout_secs: 90,
+            pool_max_idle_per_host: None,
+            upstream_ca_path: None,
+            upstream_client_cert_path: None,
+            upstream_client_key_path: None,
+            require_backend_on_startup: false,
+            upstream_tls_skip_verify: false,
+            backend_timeout_secs: 500,
+            first_byte_timeout_secs: 0,
+            response_timeout_secs: 0,
+            request_deadline_secs: 0,
+            metrics_poll_interval_secs: 10,
+            staleness_secs: 30,
+            metrics_backoff_max_secs: 60,
+            metrics_timeout_secs: 5,
+            metrics_connect_timeout_ms: 1000,
+            metrics_push_ttl_secs: 30,
+            shutdown_drain_timeout_secs: 30,
+            health_check_interval_secs: 5,
+            health_check_timeout_secs: 2,
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+            breaker_failure_threshold: 5,
+            breaker_window_secs: 30,
+            breaker_cooldown_secs: 30,
+            breaker_half_open_probes: 1,
+            failure_cooldown_ms: 0,
+            max_failover_in_flight: 0,
+            warmup_secs: 0,
+            latency_window_secs: 300,
+            kv_metrics_name: None,
+            kv_metrics_model: "tensorrt_llm".to_string(),
+            kv_metrics_version: KvMetricsVersions::One("1".to_string()),
+            kv_metrics_labels: KvMetricsLabels::default(),
+            kv_pressure_weight: 1.0,
+            kv_smoothing_factor: 1.0,
+            pressure_metrics: Vec::new(),
+            routing_strategy: RoutingStrategy::Threshold,
+            weight_floor: 0.0,
+            shadow_strategy: None,
+            rng_seed: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            session_affinity: false,
+            session_header: "x-session-id".to_string(),
+            session_ttl_secs: 600,
+            top_clients_window_secs: 60,
+            session_capacity: 10_000,
+            admin_token: None,
+            routes: Vec::new(),
+            model_pointer: None,
+            model_pools: BTreeMap::new(),
+            model_peek_max_bytes: 65536,
+            stream_paths: Vec::new(),
+            default_pool: DEFAULT_POOL.to_string(),
+            unavailable_retry_after_secs: 5,
+            reject_when_all_over_threshold: false,
+            shed_high_water_mark: None,
+            shed_max_probability: 1.0,
+            admission_queue_depth: 0,
+            admission_max_wait_secs: 5,
+            normalize_error_bodies: false,
+            gzip_responses: false,
+            gzip_min_bytes: 1024,
+            access_log: false,
+            body_log: false,
+            body_log_max_bytes: 512,
+            allow_force_backend: false,
+            served_by_header: None,
+            chaos_enabled: false,
+            strip_headers: Vec::new(),
+            host_header: HostHeader::Preserve,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: ["GET", "POST", "OPTIONS"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            cors_allowed_headers: ["content-type", "authorization"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            cors_max_age_secs: 600,
+            rate_limit_rps: None,
+            rate_limit_burst: None,
+            client_rate_limit_rps: None,
+            client_rate_limit_burst: None,
+        }
+    }
+}
+
+impl LbConfig {
+    /// Reads the config file (if present), applies `LB_*` env overrides and validates the result.
+    pub fn load() -> Result<Self, String> {
+        let path = env::var("LB_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        let mut config = if Path::new(&path).exists() {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read config file {}: {}", path, e))?;
+            toml::from_str(&text)
+                .map_err(|e| format!("failed to parse config file {}: {}", path, e))?
+        } else if env::var("LB_CONFIG").is_ok() {
+            return Err(format!("config file {} does not exist", path));
+        } else {
+            LbConfig::default()
+        };
+
+        if let Ok(value) = env::var("LB_LISTEN_ADDR") {
+            config.listen_addr = value
+                .parse()
+                .map_err(|e| format!("invalid LB_LISTEN_ADDR {:?}: {}", value, e))?;
+        }
+        if let Ok(value) = env::var("LB_CAPACITY_THRESHOLD") {
+            config.capacity_threshold = value
+                .parse()
+                .map_err(|e| format!("invalid LB_CAPACITY_THRESHOLD {:?}: {}", value, e))?;
+        }
+        if let Ok(value) = env::var("LB_RELEASE_THRESHOLD") {
+            config.release_threshold = value
+                .parse()
+                .map_err(|e| format!("invalid LB_RELEASE_THRESHOLD {:?}: {}", value, e))?;
+        }
+        if let Ok(value) = env::var("LB_METRICS_POLL_INTERVAL_SECS") {
+            config.metrics_poll_interval_secs = value
+                .parse()
+                .map_err(|e| format!("invalid LB_METRICS_POLL_INTERVAL_SECS {:?}: {}", value, e))?;
+        }
+        if let Ok(value) = env::var("LB_WORKER_THREADS") {
+            config.worker_threads = Some(
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid LB_WORKER_THREADS {:?}: {}", value, e))?,
+            );
+        }
+        if let Ok(value) = env::var("LB_THREAD_NAME") {
+            config.thread_name = value;
+        }
+        if let Ok(value) = env::var("LB_ADMIN_TOKEN") {
+            config.admin_token = Some(value);
+        }
+        config.checked()
+    }
+
+    /// The multi-threaded tokio runtime to run the load balancer on, sized by `worker_threads`.
+    pub fn runtime(&self) -> Result<Runtime, String> {
+        let mut builder = runtime::Builder::new_multi_thread();
+        builder.enable_all().thread_name(&self.thread_name);
+        if let Some(threads) = self.worker_threads {
+            builder.worker_threads(threads);
+        }
+        builder
+            .build()
+            .map_err(|e| format!("failed to start the runtime: {}", e))
+    }
+
+    /// Parses and validates a config file's contents, without the `LB_*` env overrides.
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let config: LbConfig =
+            toml::from_str(text).map_err(|e| format!("failed to parse config: {}", e))?;
+        config.checked()
+    }
+
+    /// Raises a too short poll interval to the floor, then validates.
+    pub(crate) fn checked(mut self) -> Result<Self, String> {
+        if self.metrics_poll_interval_secs < MIN_POLL_INTERVAL_SECS {
+            warn!(
+                configured = self.metrics_poll_interval_secs,
+                floor = MIN_POLL_INTERVAL_SECS,
+                "metrics_poll_interval_secs would hammer the metrics endpoints, raising it to the floor"
+            );
+            self.metrics_poll_interval_secs = MIN_POLL_INTERVAL_SECS;
+        }
+
+        self.validate()?;
+        for backend in &self.backends {
+            if parse_absolute_uri(&backend.base_uri).is_ok_and(|uri| uri.path() != "/") {
+                warn!(
+                    backend = %backend.name,
+                    base_uri = %backend.base_uri,
+                    "base_uri has a path, which is ignored: requests are forwarded with their own path"
+                );
+            }
+        }
+        Ok(self)
+    }
+
+    /// The pool serving a request for `path` whose body names `model`.
+    pub(crate) fn pool_for(&self, path: &str, model: Option<&str>) -> &str {
+        if let Some(pool) = model.and_then(|model| self.model_pools.get(model)) {
+            return pool;
+        }
+        self.routes
+            .iter()
+            .filter(|route| path.starts_with(&route.path_prefix))
+            .max_by_key(|route| route.path_prefix.len())
+            .map_or(&self.default_pool, |route| &route.pool)
+    }
+
+    /// Whether requests for `path` are streamed through as they come, under `stream_paths`.
+    pub(crate) fn streams_body(&self, path: &str) -> bool {
+        self.stream_paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    pub(crate) fn rate_limits(&self) -> (Option<RateLimit>, Option<RateLimit>) {
+        (
+            RateLimit::new(self.rate_limit_rps, self.rate_limit_burst),
+            RateLimit::new(self.client_rate_limit_rps, self.client_rate_limit_burst),
+        )
+    }
+
+    pub(crate) fn thresholds(&self, backend: &BackendConfig) -> Thresholds {
+        Thresholds {
+            capacity: backend
+                .capacity_threshold
+                .unwrap_or(self.capacity_threshold),
+            release: backend.release_threshold.unwrap_or(self.release_threshold),
+        }
+    }
+
+    /// The capacity threshold of the backend named `name`; the global one for unknown names.
+    pub(crate) fn capacity_threshold_of(&self, name: &str) -> f64 {
+        self.backends
+            .iter()
+            .find(|b| b.name == name)
+            .map_or(self.capacity_threshold, |b| self.thresholds(b).capacity)
+    }
+
+    /// Position in `tiers` of the tier of the backend named `name`; None for backends without
+    /// one and unknown names.
+    pub(crate) fn tier_rank_of(&self, name: &str) -> Option<usize> {
+        let tier = self
+            .backends
+            .iter()
+            .find(|b| b.name == name)?
+            .tier
+            .as_ref()?;
+        self.tiers.iter().position(|t| t == tier)
+    }
+
+    /// The `priority_threshold_offsets` entry for an `X-Priority` value, ignoring case; 0 for
+    /// unknown priorities.
+    pub(crate) fn priority_offset(&self, priority: &str) -> f64 {
+        self.priority_threshold_offsets
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(priority))
+            .map_or(0.0, |(_, &offset)| offset)
+    }
+
+    /// This config with `backends` in place of its own, validated again.
+    pub(crate) fn with_backends(&self, backends: Vec<BackendConfig>) -> Result<LbConfig, String> {
+        let config = LbConfig {
+            backends,
+            ..self.clone()
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub(crate) fn breaker_settings(&self) -> BreakerSettings {
+        BreakerSettings {
+            failure_threshold: self.breaker_failure_threshold,
+            window: Duration::from_secs(self.breaker_window_secs),
+            cooldown: Duration::from_secs(self.breaker_cooldown_secs),
+            half_open_probes: self.breaker_half_open_probes,
+            failure_cooldown: Duration::from_millis(self.failure_cooldown_ms),
+        }
+    }
+
+    /// The checks of a single `[[backends]]` entry.
+    fn validate_backend(&self, backend: &BackendConfig) -> Result<(), String> {
+        let base_uri = parse_absolute_uri(&backend.base_uri)
+            .map_err(|e| format!("backend {:?} has an invalid base_uri: {}", backend.name, e))?;
+        if !matches!(base_uri.scheme_str(), Some("http" | "https")) {
+            return Err(format!(
+                "backend {:?} base_uri must be http:// or https://",
+                backend.name
+            ));
+        }
+        if backend.weight == Some(0) {
+            return Err(format!(
+                "backend {:?} must have a weight of at least 1",
+                backend.name
+            ));
+        }
+        if let Some(tier) = backend
+            .tier
+            .as_ref()
+            .filter(|tier| !self.tiers.contains(tier))
+        {
+            return Err(format!(
+                "backend {:?} has tier {:?}, which is not listed in tiers",
+                backend.name, tier
+            ));
+        }
+        if let Some(url) = &backend.kv_metrics_url {
+            parse_absolute_uri(url).map_err(|e| {
+                format!(
+                    "backend {:?} has an invalid kv_metrics_url: {}",
+                    backend.name, e
+                )
+            })?;
+        }
+        if let Some(path) = &backend.health_path {
+            health_check_uri(&backend.base_uri, path).map_err(|e| {
+                format!(
+                    "backend {:?} has an invalid health_path: {}",
+                    backend.name, e
+                )
+            })?;
+        }
+        let thresholds = self.thresholds(backend);
+        if !(0.0..=1.0).contains(&thresholds.capacity) {
+            return Err(format!(
+                "backend {:?} capacity_threshold must be within 0.0..=1.0, got {}",
+                backend.name, thresholds.capacity
+            ));
+        }
+        if !(0.0..=thresholds.capacity).contains(&thresholds.release) {
+            return Err(format!(
+                "backend {:?} release_threshold must be within 0.0..=capacity_threshold ({}), got {}",
+                backend.name, thresholds.capacity, thresholds.release
+            ));
+        }
+        if backend.max_concurrency == Some(0) {
+            return Err(format!(
+                "backend {:?} max_concurrency must be at least 1",
+                backend.name
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.capacity_threshold) {
+            return Err(format!(
+                "capacity_threshold must be within 0.0..=1.0, got {}",
+                self.capacity_threshold
+            ));
+        }
+        if !(0.0..=self.capacity_threshold).contains(&self.release_threshold) {
+            return Err(format!(
+                "release_threshold must be within 0.0..=capacity_threshold ({}), got {}",
+                self.capacity_threshold, self.release_threshold
+            ));
+        }
+        for (priority, offset) in &self.priority_threshold_offsets {
+            if !(-1.0..=1.0).contains(offset) {
+                return Err(format!(
+                    "priority_threshold_offsets entry {:?} must be within -1.0..=1.0, got {}",
+                    priority, offset
+                ));
+            }
+        }
+        for (i, tier) in self.tiers.iter().enumerate() {
+            if self.tiers[..i].contains(tier) {
+                return Err(format!("duplicate tier {:?}", tier));
+            }
+        }
+        if self.backends.is_empty() && self.discovery_source.is_none() {
+            return Err("at least one backend must be configured".to_string());
+        }
+        for (i, backend) in self.backends.iter().enumerate() {
+            if self.backends[..i].iter().any(|b| b.name == backend.name) {
+                return Err(format!("duplicate backend name {:?}", backend.name));
+            }
+            self.validate_backend(backend)?;
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err("tls_cert_path and tls_key_path must be set together".to_string());
+        }
+        if self.upstream_client_cert_path.is_some() != self.upstream_client_key_path.is_some() {
+            return Err(
+                "upstream_client_cert_path and upstream_client_key_path must be set together"
+                    .to_string(),
+            );
+        }
+        if self.upstream_ca_path.is_some() && self.upstream_tls_skip_verify {
+            return Err(
+                "upstream_ca_path and upstream_tls_skip_verify are mutually exclusive".to_string(),
+            );
+        }
+        if self.kv_metrics_version.as_slice().is_empty() {
+            return Err("kv_metrics_version must list at least one version".to_string());
+        }
+        self.kv_metrics_labels.validate()?;
+        if let Some(name) = &self.default_backend {
+            if !self.backends.iter().any(|b| &b.name == name) {
+                return Err(format!(
+                    "default_backend {:?} is not a configured backend",
+                    name
+                ));
+            }
+        }
+        if let Some(source) = &self.discovery_source {
+            if self.discovery_interval_secs == 0 {
+                return Err("discovery_interval_secs must be at least 1".to_string());
+            }
+            if let DiscoverySource::DnsAddresses(_) = DiscoverySource::parse(source)? {
+                let template = self
+                    .discovery_template
+                    .as_ref()
+                    .ok_or("a dns-a:// discovery_source needs a discovery_template")?;
+                if !template.name.contains(HOST_PLACEHOLDER)
+                    || !template.base_uri.contains(HOST_PLACEHOLDER)
+                {
+                    return Err(
+                        "discovery_template name and base_uri must contain {host}".to_string()
+                    );
+                }
+                self.validate_backend(&expand_template(template, Ipv4Addr::LOCALHOST.into()))
+                    .map_err(|e| format!("invalid discovery_template: {}", e))?;
+            }
+        }
+        if self.metrics_timeout_secs == 0 {
+            return Err("metrics_timeout_secs must be at least 1".to_string());
+        }
+        if self.metrics_connect_timeout_ms == 0 {
+            return Err("metrics_connect_timeout_ms must be at least 1".to_string());
+        }
+        if self.health_check_interval_secs == 0 {
+            return Err("health_check_interval_secs must be at least 1".to_string());
+        }
+        if self.unhealthy_threshold == 0 || self.healthy_threshold == 0 {
+            return Err("unhealthy_threshold and healthy_threshold must be at least 1".to_string());
+        }
+        for (name, rps, burst) in [
+            ("rate_limit", self.rate_limit_rps, self.rate_limit_burst),
+            (
+                "client_rate_limit",
+                self.client_rate_limit_rps,
+                self.client_rate_limit_burst,
+            ),
+        ] {
+            if rps.is_some_and(|rps| !(rps.is_finite() && rps > 0.0)) {
+                return Err(format!("{}_rps must be a positive number", name));
+            }
+            if burst == Some(0) {
+                return Err(format!("{}_burst must be at least 1", name));
+            }
+        }
+        if !(self.kv_pressure_weight.is_finite() && self.kv_pressure_weight >= 0.0) {
+            return Err("kv_pressure_weight must be a non-negative number".to_string());
+        }
+        if !(self.kv_smoothing_factor > 0.0 && self.kv_smoothing_factor <= 1.0) {
+            return Err(format!(
+                "kv_smoothing_factor must be within (0.0, 1.0], got {}",
+                self.kv_smoothing_factor
+            ));
+        }
+        if let Some(mark) = self
+            .shed_high_water_mark
+            .filter(|mark| !(0.0..1.0).contains(mark))
+        {
+            return Err(format!(
+                "shed_high_water_mark must be within 0.0..1.0, got {}",
+                mark
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.shed_max_probability) {
+            return Err(format!(
+                "shed_max_probability must be within 0.0..=1.0, got {}",
+                self.shed_max_probability
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.weight_floor) {
+            return Err(format!(
+                "weight_floor must be within 0.0..=1.0, got {}",
+                self.weight_floor
+            ));
+        }
+        for metric in &self.pressure_metrics {
+            if !(metric.weight.is_finite() && metric.weight >= 0.0) {
+                return Err(format!(
+                    "pressure metric {:?} needs a non-negative weight",
+                    metric.name
+                ));
+            }
+            match (metric.max, &metric.max_metric) {
+                (Some(max), None) if max.is_finite() && max > 0.0 => {}
+                (None, Some(_)) => {}
+                _ => {
+                    return Err(format!(
+                        "pressure metric {:?} needs either a positive max or a max_metric",
+                        metric.name
+                    ))
+                }
+            }
+        }
+        if self.connect_timeout_secs == 0 {
+            return Err("connect_timeout_secs must be at least 1".to_string());
+        }
+        if self.breaker_failure_threshold == 0 {
+            return Err("breaker_failure_threshold must be at least 1".to_string());
+        }
+        if self.worker_threads == Some(0) {
+            return Err("worker_threads must be at least 1".to_string());
+        }
+        if self.thread_name.is_empty() {
+            return Err("thread_name must not be empty".to_string());
+        }
+        if self.breaker_half_open_probes == 0 {
+            return Err("breaker_half_open_probes must be at least 1".to_string());
+        }
+        if let Some(status) = self
+            .retry_on_status
+            .iter()
+            .find(|s| !(500..=599).contains(*s))
+        {
+            return Err(format!(
+                "retry_on_status entries must be 5xx statuses, got {}",
+                status
+            ));
+        }
+        if self.staleness_secs != 0 && self.staleness_secs <= self.metrics_poll_interval_secs {
+            return Err(format!(
+                "staleness_secs must exceed metrics_poll_interval_secs ({}), got {}",
+                self.metrics_poll_interval_secs, self.staleness_secs
+            ));
+        }
+        if self.latency_window_secs == 0 {
+            return Err("latency_window_secs must be at least 1".to_string());
+        }
+        HeaderName::from_bytes(self.session_header.as_bytes()).map_err(|_| {
+            format!(
+                "session_header {:?} is not a valid header name",
+                self.session_header
+            )
+        })?;
+        for name in &self.strip_headers {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                format!("strip_headers entry {:?} is not a valid header name", name)
+            })?;
+        }
+        for origin in &self.cors_allowed_origins {
+            HeaderValue::from_str(origin).map_err(|_| {
+                format!(
+                    "cors_allowed_origins entry {:?} is not a valid header value",
+                    origin
+                )
+            })?;
+        }
+        for method in &self.cors_allowed_methods {
+            Method::from_bytes(method.as_bytes()).map_err(|_| {
+                format!(
+                    "cors_allowed_methods entry {:?} is not a valid method",
+                    method
+                )
+            })?;
+        }
+        if let Some(name) = &self.served_by_header {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("served_by_header {:?} is not a valid header name", name))?;
+        }
+        for name in &self.cors_allowed_headers {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                format!(
+                    "cors_allowed_headers entry {:?} is not a valid header name",
+                    name
+                )
+            })?;
+        }
+        if self.body_log_max_bytes > MAX_BODY_LOG_BYTES {
+            return Err(format!(
+                "body_log_max_bytes must be at most {}, got {}",
+                MAX_BODY_LOG_BYTES, self.body_log_max_bytes
+            ));
+        }
+        if self.top_clients_window_secs == 0 {
+            return Err("top_clients_window_secs must be at least 1".to_string());
+        }
+        if self.session_capacity == 0 {
+            return Err("session_capacity must be at least 1".to_string());
+        }
+        // Discovered backends may fill a pool later.
+        let pools = self
+            .routes
+            .iter()
+            .map(|r| &r.pool)
+            .chain(self.model_pools.values())
+            .chain([&self.default_pool]);
+        for pool in pools.filter(|_| self.discovery_source.is_none()) {
+            if !self.backends.iter().any(|b| b.pool() == pool) {
+                return Err(format!("pool {:?} has no backends", pool));
+            }
+        }
+        if let Some(route) = self.routes.iter().find(|r| !r.path_prefix.starts_with('/')) {
+            return Err(format!(
+                "route path_prefix {:?} must start with '/'",
+                route.path_prefix
+            ));
+        }
+        if let Some(prefix) = self
+            .stream_paths
+            .iter()
+            .find(|prefix| !prefix.starts_with('/'))
+        {
+            return Err(format!(
+                "stream_paths entry {:?} must start with '/'",
+                prefix
+            ));
+        }
+        match &self.model_pointer {
+            Some(pointer) if !pointer.is_empty() && !pointer.starts_with('/') => {
+                return Err(format!(
+                    "model_pointer {:?} must be a JSON pointer starting with '/'",
+                    pointer
+                ));
+            }
+            None if !self.model_pools.is_empty() => {
+                return Err(
+                    "model_pools needs a model_pointer to find the model in request bodies"
+                        .to_string(),
+                );
+            }
+            _ => {}
+        }
+        if let Some(token) = &self.admin_token {
+            if token.is_empty() || HeaderValue::from_str(token).is_err() {
+                return Err("admin_token must be a non-empty header value".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a URI that must name a scheme and a host, as every configured backend URL does.
+pub(crate) fn parse_absolute_uri(uri: &str) -> Result<Uri, String> {
+    let parsed = Uri::from_str(uri).map_err(|e| format!("{:?}: {}", uri, e))?;
+    if parsed.scheme().is_none() || parsed.authority().is_none() {
+        return Err(format!("{:?} must include a scheme and host", uri));
+    }
+    Ok(parsed)
+}
+
+/// Joins a backend's scheme and host with its health check path.
+pub(crate) fn health_check_uri(base_uri: &str, path: &str) -> Result<Uri, String> {
+    if !path.starts_with('/') {
+        return Err(format!("{:?} must start with '/'", path));
+    }
+    let base = parse_absolute_uri(base_uri)?;
+    let mut parts = base.into_parts();
+    parts.path_and_query = Some(path.parse().map_err(|e| format!("{:?}: {}", path, e))?);
+    Uri::from_parts(parts).map_err(|e| format!("{:?}: {}", path, e))
+}
+
+/// What a backend's metrics endpoint serves.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MetricsFormat {
+    /// Prometheus exposition text with the KV cache block gauges, like Triton's `/metrics`.
+    PrometheusText,
+    /// A JSON object with numeric `kv_used` and `kv_max` fields, e.g. a `/stats` endpoint.
+    Json,
+}
+
+/// How the `Host` header of forwarded requests is set.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HostHeader {
+    /// Forward the client's `Host` unchanged.
+    Preserve,
+    /// Replace it with the host and port of the backend's `base_uri`, for backends that route
+    /// by virtual host.
+    Upstream,
+}
+
+/// The `version` labels whose KV cache blocks count: one version, or several whose used and
+/// max blocks are added up, e.g. while a model upgrade has both versions loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum KvMetricsVersions {
+    One(String),
+    Several(Vec<String>),
+}
+
+impl KvMetricsVersions {
+    pub(crate) fn as_slice(&self) -> &[String] {
+        match self {
+            KvMetricsVersions::One(version) => std::slice::from_ref(version),
+            KvMetricsVersions::Several(versions) => versions,
+        }
+    }
+}
+
+/// How the KV cache block gauges are labelled. `model` and `version` may be empty for gauges
+/// without such a label, which then aren't told apart by it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct KvMetricsLabels {
+    /// Label telling the used blocks gauge from the max blocks one.
+    pub(crate) block_type: String,
+    /// Its value on the used blocks gauge.
+    pub(crate) used: String,
+    /// Its value on the max blocks gauge.
+    pub(crate) max: String,
+    /// Label compared with `kv_metrics_model`.
+    pub(crate) model: String,
+    /// Label compared with `kv_metrics_version`.
+    pub(crate) version: String,
+    /// Further labels a sample must carry to count, e.g. `{ gpu = "0" }`.
+    pub(crate) required: BTreeMap<String, String>,
+}
+
+impl Default for KvMetricsLabels {
+    fn default() -> Self {
+        KvMetricsLabels {
+            block_type: "kv_cache_block_type".to_string(),
+            used: "used".to_string(),
+            max: "max".to_string(),
+            model: "model".to_string(),
+            version: "version".to_string(),
+            required: BTreeMap::new(),
+        }
+    }
+}
+
+impl KvMetricsLabels {
+    fn validate(&self) -> Result<(), String> {
+        let fixed = [&self.block_type, &self.model, &self.version];
+        let names = fixed
+            .iter()
+            .copied()
+            .filter(|name| !name.is_empty())
+            .chain(self.required.keys());
+        let mut seen: Vec<&String> = Vec::new();
+        for name in names {
+            if !is_label_name(name) {
+                return Err(format!(
+                    "kv_metrics_labels: {:?} is not a valid label name",
+                    name
+                ));
+            }
+            if seen.contains(&name) {
+                return Err(format!(
+                    "kv_metrics_labels uses the label {:?} more than once",
+                    name
+                ));
+            }
+            seen.push(name);
+        }
+        if self.block_type.is_empty() {
+            return Err("kv_metrics_labels.block_type must not be empty".to_string());
+        }
+        if self.used.is_empty() || self.max.is_empty() || self.used == self.max {
+            return Err(
+                "kv_metrics_labels.used and max must be different, non-empty values".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` is a Prometheus label name: `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// HTTP version used for requests to a backend.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BackendProtocol {
+    Http1,
+    /// HTTP/2 over cleartext with prior knowledge, so requests are multiplexed on few connections.
+    H2c,
+}
+
+/// How `select_backend` chooses among the online backends.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RoutingStrategy {
+    /// Stay on the primary until it sheds load, then spill to the least loaded backend.
+    Threshold,
+    /// Always pick the backend with the lowest pressure score.
+    LeastLoaded,
+    /// Pick at random, weighted by each backend's headroom `1 - pressure`.
+    WeightedRandom,
+    /// Pick the backend with the fewest in-flight requests, breaking ties by pressure.
+    LeastConnections,
+    /// Walk the pool in configured order and take the first backend that isn't shedding load.
+    FailoverOrder,
+    /// Pick the backend with the most free KV cache blocks in absolute terms, so bigger GPUs
+    /// take more of the traffic, breaking ties by pressure.
+    MostFreeBlocks,
+}
+
+impl RoutingStrategy {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            RoutingStrategy::Threshold => "threshold",
+            RoutingStrategy::LeastLoaded => "least_loaded",
+            RoutingStrategy::WeightedRandom => "weighted_random",
+            RoutingStrategy::LeastConnections => "least_connections",
+            RoutingStrategy::FailoverOrder => "failover_order",
+            RoutingStrategy::MostFreeBlocks => "most_free_blocks",
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! CORS for browser clients: answering preflight requests and letting allowed origins read
+//! the proxied responses.
+
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::config::LbConfig;
+
+/// Whether `req` is a browser's preflight check rather than a real request.
+pub(crate) fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// The `Access-Control-Allow-Origin` value for a request with these headers: `*` when any
+/// origin is allowed, the request's own `Origin` when it is listed, None otherwise.
+pub(crate) fn allowed_origin(config: &LbConfig, headers: &HeaderMap) -> Option<HeaderValue> {
+    let origins = &config.cors_allowed_origins;
+    if origins.iter().any(|o| o == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+    let origin = headers.get(ORIGIN)?;
+    origins
+        .iter()
+        .any(|o| o.as_bytes() == origin.as_bytes())
+        .then(|| origin.clone())
+}
+
+/// The `204` answering a preflight request. Without an allowed `origin` it carries no CORS
+/// headers, so the browser refuses the real request.
+pub(crate) fn preflight_response(config: &LbConfig, origin: Option<HeaderValue>) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+    if let Some(origin) = origin {
+        let headers = resp.headers_mut();
+        add_cors_headers(headers, origin);
+        // Validated on load, so these are valid header values.
+        if let Ok(methods) = HeaderValue::from_str(&config.cors_allowed_methods.join(", ")) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Ok(allowed) = HeaderValue::from_str(&config.cors_allowed_headers.join(", ")) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        headers.insert(
+            ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from(config.cors_max_age_secs),
+        );
+    }
+    resp
+}
+
+/// Lets `origin` read a response; replaces any CORS headers the backend sent.
+pub(crate) fn add_cors_headers(headers: &mut HeaderMap, origin: HeaderValue) {
+    if origin != "*" {
+        // Caches must not serve a response allowing one origin to another.
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(origins: &str) -> LbConfig {
+        LbConfig::from_toml(&format!("cors_allowed_origins = {}", origins))
+            .expect("valid test config")
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/v2/models/ensemble/generate")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn only_listed_origins_are_allowed() {
+        let config = config(r#"["https://ui.example.com"]"#);
+        let listed = preflight("https://ui.example.com");
+        let other = preflight("https://evil.example.com");
+        assert!(is_preflight(&listed));
+        assert_eq!(
+            allowed_origin(&config, listed.headers()).unwrap(),
+            "https://ui.example.com"
+        );
+        assert_eq!(allowed_origin(&config, other.headers()), None);
+        assert_eq!(allowed_origin(&config, &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn a_wildcard_allows_any_origin() {
+        let config = config(r#"["*"]"#);
+        let origin = allowed_origin(&config, preflight("https://ui.example.com").headers());
+        let resp = preflight_response(&config, origin);
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            resp.headers()[ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, POST, OPTIONS"
+        );
+        assert_eq!(resp.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+        assert!(resp.headers().get(VARY).is_none());
+    }
+
+    #[test]
+    fn disallowed_preflights_get_no_cors_headers() {
+        let config = config(r#"["https://ui.example.com"]"#);
+        let resp = preflight_response(&config, None);
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(resp.headers().is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+//! Backends found at runtime from `discovery_source`, on top of the configured ones. Each
+//! refresh that changes the list goes through the same reconciliation as a reload: new
+//! backends get their poll and health loops, removed ones finish their in-flight requests.
+
+use hyper::header::USER_AGENT;
+use hyper::{Body, Request};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::lookup_host;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{info, warn};
+
+use crate::config::BackendConfig;
+use crate::lb_metrics::LbMetrics;
+use crate::upstream::{UpstreamClient, UpstreamClients};
+use crate::{replace_backends, AppState};
+
+/// Stands for a discovered address in `discovery_template`.
+pub(crate) const HOST_PLACEHOLDER: &str = "{host}";
+/// Longest a single lookup or fetch of the source may take.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DISCOVERY_USER_AGENT: &str = concat!("rust-lb-discovery/", env!("CARGO_PKG_VERSION"));
+
+/// Where `discovery_source` points.
+#[derive(Debug, PartialEq)]
+pub(crate) enum DiscoverySource {
+    /// A JSON array of backends, in the format of `[[backends]]`.
+    File(PathBuf),
+    /// The same array, served over HTTP.
+    Url(String),
+    /// A name whose A/AAAA records are the backends, expanded from `discovery_template`. SRV
+    /// records aren't looked up, so the ports come from the template too.
+    DnsAddresses(String),
+}
+
+impl DiscoverySource {
+    pub(crate) fn parse(source: &str) -> Result<Self, String> {
+        if let Some(name) = source.strip_prefix("dns-a://") {
+            let name = name.trim_end_matches('/');
+            if name.is_empty() || name.contains(['/', ':']) {
+                return Err(format!(
+                    "discovery_source {:?} must be dns-a://<name> without a port or path",
+                    source
+                ));
+            }
+            Ok(DiscoverySource::DnsAddresses(name.to_string()))
+        } else if source.starts_with("dns://") {
+            Err(format!(
+                "discovery_source {:?}: SRV lookups aren't supported, use dns-a://<name> for its A/AAAA records",
+                source
+            ))
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            source
+                .parse::<hyper::Uri>()
+                .map_err(|e| format!("discovery_source {:?} is not a valid URL: {}", source, e))?;
+            Ok(DiscoverySource::Url(source.to_string()))
+        } else {
+            let path = source.strip_prefix("file://").unwrap_or(source);
+            if path.is_empty() {
+                return Err("discovery_source must not be empty".to_string());
+            }
+            Ok(DiscoverySource::File(PathBuf::from(path)))
+        }
+    }
+}
+
+/// `template` for the backend at `ip`. URIs get IPv6 addresses in brackets.
+pub(crate) fn expand_template(template: &BackendConfig, ip: IpAddr) -> BackendConfig {
+    let host = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    BackendConfig {
+        name: template.name.replace(HOST_PLACEHOLDER, &ip.to_string()),
+        base_uri: template.base_uri.replace(HOST_PLACEHOLDER, &host),
+        kv_metrics_url: template
+            .kv_metrics_url
+            .as_ref()
+            .map(|url| url.replace(HOST_PLACEHOLDER, &host)),
+        ..template.clone()
+    }
+}
+
+/// The configured backends followed by the discovered ones not named like one of them.
+pub(crate) fn merge(
+    configured: &[BackendConfig],
+    discovered: &[BackendConfig],
+) -> Vec<BackendConfig> {
+    let mut backends = configured.to_vec();
+    for backend in discovered {
+        if configured.iter().any(|b| b.name == backend.name) {
+            warn!(backend = %backend.name, "discovered backend has the name of a configured one, ignoring it");
+        } else {
+            backends.push(backend.clone());
+        }
+    }
+    backends
+}
+
+/// Reads `discovery_source` every `discovery_interval_secs` and applies what it lists. A
+/// failed read keeps the backends found before.
+pub(crate) async fn discovery_loop(
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+) {
+    loop {
+        let (source, template, interval) = {
+            let state = app_state.read().await;
+            let config = &state.config;
+            (
+                config.discovery_source.clone(),
+                config.discovery_template.clone(),
+                Duration::from_secs(config.discovery_interval_secs),
+            )
+        };
+        if let Some(source) = source {
+            match discover(&source, template.as_ref(), &clients.http1).await {
+                Ok(found) => apply(&app_state, &clients, &lb_metrics, found).await,
+                Err(e) => {
+                    warn!(source = %source, error = %e, "backend discovery failed, keeping the backends found before")
+                }
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+async fn discover(
+    source: &str,
+    template: Option<&BackendConfig>,
+    client: &UpstreamClient,
+) -> Result<Vec<BackendConfig>, String> {
+    match DiscoverySource::parse(source)? {
+        DiscoverySource::File(path) => {
+            let text = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            parse_backend_list(&text)
+        }
+        DiscoverySource::Url(url) => {
+            let req = Request::get(&url)
+                .header(USER_AGENT, DISCOVERY_USER_AGENT)
+                .body(Body::empty())
+                .map_err(|e| format!("failed to build request: {}", e))?;
+            let fetch = async {
+                let resp = client.request(req).await.map_err(|e| e.to_string())?;
+                if !resp.status().is_success() {
+                    return Err(format!("discovery endpoint returned {}", resp.status()));
+                }
+                hyper::body::to_bytes(resp.into_body())
+                    .await
+                    .map_err(|e| format!("failed to read body: {}", e))
+            };
+            let body = timeout(DISCOVERY_TIMEOUT, fetch)
+                .await
+                .map_err(|_| format!("timed out after {}s", DISCOVERY_TIMEOUT.as_secs()))??;
+            parse_backend_list(&String::from_utf8_lossy(&body))
+        }
+        DiscoverySource::DnsAddresses(name) => {
+            let template =
+                template.ok_or("a dns-a:// discovery_source needs a discovery_template")?;
+            let addrs = timeout(DISCOVERY_TIMEOUT, lookup_host((name.as_str(), 0)))
+                .await
+                .map_err(|_| format!("lookup timed out after {}s", DISCOVERY_TIMEOUT.as_secs()))?
+                .map_err(|e| format!("lookup of {} failed: {}", name, e))?;
+            // Sorted, so a reordered answer doesn't count as a change.
+            let mut ips: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+            ips.sort();
+            ips.dedup();
+            Ok(ips
+                .into_iter()
+                .map(|ip| expand_template(template, ip))
+                .collect())
+        }
+    }
+}
+
+fn parse_backend_list(text: &str) -> Result<Vec<BackendConfig>, String> {
+    serde_json::from_str(text).map_err(|e| format!("invalid backend list: {}", e))
+}
+
+/// Makes `found` the discovered backends, unless they are unchanged or fail validation.
+async fn apply(
+    app_state: &Arc<RwLock<AppState>>,
+    clients: &Arc<UpstreamClients>,
+    lb_metrics: &Arc<LbMetrics>,
+    found: Vec<BackendConfig>,
+) {
+    let mut guard = app_state.write().await;
+    let state = &mut *guard;
+    if found == state.discovered {
+        return;
+    }
+    let config = match state
+        .config
+        .with_backends(merge(&state.configured_backends, &found))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            warn!(error = %e, "discovered backends are invalid, keeping the backends found before");
+            return;
+        }
+    };
+    if let Err(e) = replace_backends(state, &config.backends, app_state, clients, lb_metrics) {
+        warn!(error = %e, "discovered backends are invalid, keeping the backends found before");
+        return;
+    }
+    info!(discovered = found.len(), "discovered backends changed");
+    state.config = config;
+    state.discovered = found;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn sources_are_told_apart_by_scheme() {
+        assert_eq!(
+            DiscoverySource::parse("dns-a://triton.svc.cluster.local"),
+            Ok(DiscoverySource::DnsAddresses(
+                "triton.svc.cluster.local".to_string()
+            ))
+        );
+        assert_eq!(
+            DiscoverySource::parse("https://registry/backends.json"),
+            Ok(DiscoverySource::Url(
+                "https://registry/backends.json".to_string()
+            ))
+        );
+        assert_eq!(
+            DiscoverySource::parse("file:///etc/lb/backends.json"),
+            Ok(DiscoverySource::File(PathBuf::from(
+                "/etc/lb/backends.json"
+            )))
+        );
+        assert_eq!(
+            DiscoverySource::parse("backends.json"),
+            Ok(DiscoverySource::File(PathBuf::from("backends.json")))
+        );
+        assert!(DiscoverySource::parse("dns-a://triton:8000").is_err());
+        assert!(DiscoverySource::parse("dns://_http._tcp.triton").is_err());
+    }
+
+    #[test]
+    fn templates_bracket_ipv6_hosts_in_uris() {
+        let template: BackendConfig = serde_json::from_str(
+            r#"{"name":"triton-{host}","base_uri":"http://{host}:8000","kv_metrics_url":"http://{host}:8002/metrics"}"#,
+        )
+        .unwrap();
+        let backend = expand_template(&template, Ipv6Addr::LOCALHOST.into());
+        assert_eq!(backend.name, "triton-::1");
+        assert_eq!(backend.base_uri, "http://[::1]:8000");
+        assert_eq!(
+            backend.kv_metrics_url.as_deref(),
+            Some("http://[::1]:8002/metrics")
+        );
+    }
+}
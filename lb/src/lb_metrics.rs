@@ -0,0 +1,164 @@
+//! Prometheus metrics about the load balancer itself, served on `GET /metrics`.
+
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+pub struct LbMetrics {
+    registry: Registry,
+    /// Requests forwarded to each backend, counting every failover attempt.
+    pub requests_total: IntCounterVec,
+    /// Latest KV cache usage ratio scraped from each backend.
+    pub backend_kv_ratio: GaugeVec,
+    /// Latest pressure score (KV ratio blended with the configured pressure metrics) of each
+    /// backend.
+    pub backend_pressure: GaugeVec,
+    /// p50/p95/p99 time each backend took to finish its responses over the latency window.
+    pub backend_latency_seconds: GaugeVec,
+    /// Time from receiving a request until the backend's response headers (or an error) are
+    /// returned.
+    pub request_duration_seconds: Histogram,
+    /// Proxied requests currently waiting on a backend.
+    pub requests_in_flight: IntGauge,
+    /// Requests where `shadow_strategy` agreed or disagreed with the active strategy.
+    pub shadow_decisions_total: IntCounterVec,
+    /// Failed metrics scrapes of each backend, by reason (timeout, connection, bad_response).
+    pub metrics_scrape_errors_total: IntCounterVec,
+    /// Proxied requests by how they ended, and the backend they last went to ("" for none).
+    pub request_outcome_total: IntCounterVec,
+}
+
+impl LbMetrics {
+    pub fn new() -> Self {
+        let requests_total = IntCounterVec::new(
+            Opts::new("lb_requests_total", "Requests forwarded to each backend."),
+            &["backend"],
+        )
+        .expect("valid lb_requests_total definition");
+        let backend_kv_ratio = GaugeVec::new(
+            Opts::new(
+                "lb_backend_kv_ratio",
+                "Latest KV cache usage ratio of each backend.",
+            ),
+            &["backend"],
+        )
+        .expect("valid lb_backend_kv_ratio definition");
+        let backend_pressure = GaugeVec::new(
+            Opts::new(
+                "lb_backend_pressure",
+                "Latest pressure score of each backend.",
+            ),
+            &["backend"],
+        )
+        .expect("valid lb_backend_pressure definition");
+        let backend_latency_seconds = GaugeVec::new(
+            Opts::new(
+                "lb_backend_latency_seconds",
+                "Recent time each backend took to finish its responses, by quantile.",
+            ),
+            &["backend", "quantile"],
+        )
+        .expect("valid lb_backend_latency_seconds definition");
+        let request_duration_seconds = Histogram::with_opts(HistogramOpts::new(
+            "lb_request_duration_seconds",
+            "Time until the backend's response headers are returned to the client.",
+        ))
+        .expect("valid lb_request_duration_seconds definition");
+        let requests_in_flight = IntGauge::new(
+            "lb_requests_in_flight",
+            "Proxied requests currently waiting on a backend.",
+        )
+        .expect("valid lb_requests_in_flight definition");
+        let shadow_decisions_total = IntCounterVec::new(
+            Opts::new(
+                "lb_shadow_decisions_total",
+                "Requests where the shadow strategy agreed or disagreed with the active one.",
+            ),
+            &["outcome"],
+        )
+        .expect("valid lb_shadow_decisions_total definition");
+        let metrics_scrape_errors_total = IntCounterVec::new(
+            Opts::new(
+                "lb_metrics_scrape_errors_total",
+                "Failed metrics scrapes of each backend, by reason.",
+            ),
+            &["backend", "reason"],
+        )
+        .expect("valid lb_metrics_scrape_errors_total definition");
+        let request_outcome_total = IntCounterVec::new(
+            Opts::new(
+                "lb_request_outcome_total",
+                "Proxied requests by outcome and the backend they last went to.",
+            ),
+            &["outcome", "backend"],
+        )
+        .expect("valid lb_request_outcome_total definition");
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(requests_total.clone()))
+            .expect("lb_requests_total registered once");
+        registry
+            .register(Box::new(backend_kv_ratio.clone()))
+            .expect("lb_backend_kv_ratio registered once");
+        registry
+            .register(Box::new(backend_pressure.clone()))
+            .expect("lb_backend_pressure registered once");
+        registry
+            .register(Box::new(backend_latency_seconds.clone()))
+            .expect("lb_backend_latency_seconds registered once");
+        registry
+            .register(Box::new(request_duration_seconds.clone()))
+            .expect("lb_request_duration_seconds registered once");
+        registry
+            .register(Box::new(requests_in_flight.clone()))
+            .expect("lb_requests_in_flight registered once");
+        registry
+            .register(Box::new(shadow_decisions_total.clone()))
+            .expect("lb_shadow_decisions_total registered once");
+        registry
+            .register(Box::new(metrics_scrape_errors_total.clone()))
+            .expect("lb_metrics_scrape_errors_total registered once");
+        registry
+            .register(Box::new(request_outcome_total.clone()))
+            .expect("lb_request_outcome_total registered once");
+
+        LbMetrics {
+            registry,
+            requests_total,
+            backend_kv_ratio,
+            backend_pressure,
+            backend_latency_seconds,
+            request_duration_seconds,
+            requests_in_flight,
+            shadow_decisions_total,
+            metrics_scrape_errors_total,
+            request_outcome_total,
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        // Encoding into a Vec only fails on malformed metric families, which we never register.
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+/// Keeps a gauge incremented for as long as the guard is alive, including on early returns.
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
@@ -0,0 +1,1396 @@
+//! A load balancer for Triton/TensorRT-LLM inference servers that routes on their KV cache
+//! usage. `run` starts it from a config; the binary wraps it with signal handling.
+
+mod breaker;
+mod config;
+mod cors;
+mod discovery;
+mod lb_metrics;
+mod metrics;
+mod rate_limit;
+mod routing;
+mod selector;
+mod top_clients;
+mod upstream;
+
+use futures_util::future::poll_fn;
+use futures_util::stream;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::IntGauge;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::config::BackendConfig;
+use crate::lb_metrics::LbMetrics;
+use crate::metrics::{
+    record_load, refresh_latency_gauges, self_test, spawn_backend_tasks, staleness_loop, Backend,
+    InjectedFailure, MetricsState, LATENCY_QUANTILES,
+};
+use crate::rate_limit::RateLimiter;
+use crate::routing::{payload_too_large, read_body_limited, route_request, SessionMap};
+use crate::selector::StrategySelector;
+use crate::top_clients::ClientCounts;
+use crate::upstream::UpstreamClients;
+
+pub use config::LbConfig;
+pub use selector::{BackendView, RequestContext, Selector};
+
+/// Clients that haven't finished the TLS handshake by then are dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const X_ADMIN_TOKEN: HeaderName = HeaderName::from_static("x-admin-token");
+/// Clients listed by `GET /admin/top-clients` without a `limit`.
+const DEFAULT_TOP_CLIENTS: usize = 10;
+/// Pushed KV stats are a couple of numbers; anything bigger is refused.
+const MAX_PUSH_BODY_BYTES: u64 = 4096;
+
+/// Everything the poller and the request handlers share behind one lock.
+struct AppState {
+    config: LbConfig,
+    /// `config.backends` as configured, before the discovered backends were added.
+    configured_backends: Vec<BackendConfig>,
+    /// Backends last found at `discovery_source`.
+    discovered: Vec<BackendConfig>,
+    metrics: MetricsState,
+    /// Randomness for the warmup ramp and load shedding; seeded from `rng_seed` when set.
+    rng: Mutex<StdRng>,
+    sessions: Mutex<SessionMap>,
+    /// Picks the backend for each request: `routing_strategy`, unless `run_with_selector`
+    /// was given one.
+    selector: Arc<dyn Selector>,
+    /// Whether `selector` came from `run_with_selector`, and so survives reloads.
+    custom_selector: bool,
+    /// Evaluates `shadow_strategy`, with its own tie rotation so the real one doesn't shift.
+    shadow_selector: Option<StrategySelector>,
+    rate_limiter: Mutex<RateLimiter>,
+    /// Recent requests per client IP, for `GET /admin/top-clients`.
+    client_counts: Mutex<ClientCounts>,
+    /// One permit per place in the admission queue; replaced when a reload resizes the queue.
+    admission: Arc<Semaphore>,
+    /// Woken whenever a backend stops shedding load or frees a concurrency permit, for the
+    /// requests in the admission queue.
+    load_released: Arc<Notify>,
+    /// Client connections open on `listen_addr`.
+    connections: Arc<Connections>,
+}
+
+/// Counts the client connections of the main listener and, with `max_connections`, caps them.
+struct Connections {
+    active: AtomicUsize,
+    max: usize,
+    /// One permit per connection allowed; None without a limit.
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl Connections {
+    fn new(max: usize) -> Self {
+        Connections {
+            active: AtomicUsize::new(0),
+            max,
+            permits: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Admits a new connection, or None when `max_connections` are already open.
+    fn open(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        let permit = match &self.permits {
+            Some(permits) => Some(permits.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionGuard {
+            connections: self.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// Held for as long as a client connection is open.
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AppState {
+    fn new(config: LbConfig, selector: Option<Arc<dyn Selector>>) -> Result<Self, String> {
+        let metrics = MetricsState::new(&config.backends)?;
+        let rng = match config.rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let sessions = SessionMap::new(
+            config.session_capacity,
+            Duration::from_secs(config.session_ttl_secs),
+        );
+        let custom_selector = selector.is_some();
+        let selector = selector.unwrap_or_else(|| {
+            Arc::new(StrategySelector::new(
+                config.routing_strategy,
+                config.rng_seed,
+                config.weight_floor,
+            ))
+        });
+        let shadow_selector = config
+            .shadow_strategy
+            .map(|s| StrategySelector::new(s, None, config.weight_floor));
+        let admission_queue_depth = config.admission_queue_depth;
+        let connections = Arc::new(Connections::new(config.max_connections));
+        let client_counts = ClientCounts::new(Duration::from_secs(config.top_clients_window_secs));
+        Ok(AppState {
+            configured_backends: config.backends.clone(),
+            discovered: Vec::new(),
+            config,
+            metrics,
+            rng: Mutex::new(rng),
+            sessions: Mutex::new(sessions),
+            selector,
+            custom_selector,
+            shadow_selector,
+            rate_limiter: Mutex::new(RateLimiter::new()),
+            client_counts: Mutex::new(client_counts),
+            admission: Arc::new(Semaphore::new(admission_queue_depth)),
+            load_released: Arc::new(Notify::new()),
+            connections,
+        })
+    }
+}
+
+/// Locks a std mutex, carrying on with the data if a previous holder panicked.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reloads the config file every time the process receives SIGHUP.
+async fn reload_on_sighup(
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!(error = %e, "failed to install SIGHUP handler, config reload disabled");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        if let Err(e) = reload_config(&app_state, &clients, &lb_metrics).await {
+            error!(error = %e, "config reload failed, keeping the current configuration");
+        }
+    }
+}
+
+/// Loads and validates the config again and swaps it into the shared state. Settings of the
+/// listener itself only take effect on restart. Returns the fields that changed as
+/// `{"changed": {field: {"old", "new"}}, "restart_required": [field, ...]}`.
+async fn reload_config(
+    app_state: &Arc<RwLock<AppState>>,
+    clients: &Arc<UpstreamClients>,
+    lb_metrics: &Arc<LbMetrics>,
+) -> Result<serde_json::Value, String> {
+    let config = LbConfig::load()?;
+    let mut guard = app_state.write().await;
+    let state = &mut *guard;
+    let configured = config.backends.clone();
+    let discovered = if config.discovery_source.is_some() {
+        state.discovered.clone()
+    } else {
+        Vec::new()
+    };
+    let mut config = config.with_backends(discovery::merge(&configured, &discovered))?;
+    let requested = redacted_config(&config).map_err(|e| e.to_string())?;
+    let old = &state.config;
+    if config.listen_addr != old.listen_addr
+        || config.admin_listener != old.admin_listener
+        || config.admin_listen_addr != old.admin_listen_addr
+        || config.tls_cert_path != old.tls_cert_path
+        || config.tls_key_path != old.tls_key_path
+        || config.connect_timeout_secs != old.connect_timeout_secs
+        || config.metrics_connect_timeout_ms != old.metrics_connect_timeout_ms
+        || config.pool_idle_timeout_secs != old.pool_idle_timeout_secs
+        || config.pool_max_idle_per_host != old.pool_max_idle_per_host
+        || config.upstream_ca_path != old.upstream_ca_path
+        || config.upstream_tls_skip_verify != old.upstream_tls_skip_verify
+        || config.upstream_client_cert_path != old.upstream_client_cert_path
+        || config.upstream_client_key_path != old.upstream_client_key_path
+        || config.max_connections != old.max_connections
+        || config.worker_threads != old.worker_threads
+        || config.thread_name != old.thread_name
+    {
+        warn!("listen addresses, TLS, connection, upstream connection and runtime settings cannot be reloaded, restart to apply them");
+        config.listen_addr = old.listen_addr;
+        config.admin_listener = old.admin_listener;
+        config.admin_listen_addr = old.admin_listen_addr;
+        config.tls_cert_path = old.tls_cert_path.clone();
+        config.tls_key_path = old.tls_key_path.clone();
+        config.connect_timeout_secs = old.connect_timeout_secs;
+        config.metrics_connect_timeout_ms = old.metrics_connect_timeout_ms;
+        config.pool_idle_timeout_secs = old.pool_idle_timeout_secs;
+        config.pool_max_idle_per_host = old.pool_max_idle_per_host;
+        config.upstream_ca_path = old.upstream_ca_path.clone();
+        config.upstream_tls_skip_verify = old.upstream_tls_skip_verify;
+        config.upstream_client_cert_path = old.upstream_client_cert_path.clone();
+        config.upstream_client_key_path = old.upstream_client_key_path.clone();
+        config.max_connections = old.max_connections;
+        config.worker_threads = old.worker_threads;
+        config.thread_name = old.thread_name.clone();
+    }
+
+    let previous = redacted_config(&state.config).map_err(|e| e.to_string())?;
+    let applied = redacted_config(&config).map_err(|e| e.to_string())?;
+    let changed = config_diff(&previous, &applied);
+    let restart_required: Vec<String> = config_diff(&requested, &applied)
+        .into_iter()
+        .map(|(field, _)| field)
+        .collect();
+    let summary = json!({ "changed": changed, "restart_required": restart_required });
+
+    replace_backends(state, &config.backends, app_state, clients, lb_metrics)?;
+    state.configured_backends = configured;
+    state.discovered = discovered;
+
+    {
+        let mut sessions = lock(&state.sessions);
+        sessions.ttl = Duration::from_secs(config.session_ttl_secs);
+        sessions
+            .entries
+            .resize(NonZeroUsize::new(config.session_capacity).unwrap_or(NonZeroUsize::MIN));
+    }
+    lock(&state.client_counts).window = Duration::from_secs(config.top_clients_window_secs);
+    if config.admission_queue_depth != state.config.admission_queue_depth {
+        // Requests already queued keep their permits from the old queue.
+        state.admission = Arc::new(Semaphore::new(config.admission_queue_depth));
+    }
+    if !state.custom_selector
+        && (config.routing_strategy != state.config.routing_strategy
+            || config.rng_seed != state.config.rng_seed
+            || config.weight_floor != state.config.weight_floor)
+    {
+        state.selector = Arc::new(StrategySelector::new(
+            config.routing_strategy,
+            config.rng_seed,
+            config.weight_floor,
+        ));
+    }
+    if config.shadow_strategy != state.config.shadow_strategy
+        || config.weight_floor != state.config.weight_floor
+    {
+        state.shadow_selector = config
+            .shadow_strategy
+            .map(|s| StrategySelector::new(s, None, config.weight_floor));
+    }
+    info!(
+        capacity_threshold = config.capacity_threshold,
+        release_threshold = config.release_threshold,
+        routing_strategy = config.routing_strategy.as_str(),
+        backends = state.metrics.backends.len(),
+        changed = ?changed.keys().collect::<Vec<_>>(),
+        "configuration reloaded"
+    );
+    state.config = config;
+    Ok(summary)
+}
+
+/// The config as JSON, with the admin token hidden.
+fn redacted_config(config: &LbConfig) -> serde_json::Result<serde_json::Value> {
+    let mut value = serde_json::to_value(config)?;
+    if config.admin_token.is_some() {
+        value["admin_token"] = json!("<redacted>");
+    }
+    Ok(value)
+}
+
+/// The top-level fields of `new` whose values differ in `old`, with both values.
+fn config_diff(
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+) -> serde_json::Map<String, serde_json::Value> {
+    let (Some(old), Some(new)) = (old.as_object(), new.as_object()) else {
+        return serde_json::Map::new();
+    };
+    new.iter()
+        .filter(|(field, value)| old.get(*field) != Some(value))
+        .map(|(field, value)| {
+            (
+                field.clone(),
+                json!({ "old": old.get(field), "new": value }),
+            )
+        })
+        .collect()
+}
+
+/// Swaps the backend list for `backends`. Backends that stay keep their state, new ones get
+/// their poll and health loops, and removed ones finish their in-flight requests. On error
+/// nothing changes.
+fn replace_backends(
+    state: &mut AppState,
+    backends: &[BackendConfig],
+    app_state: &Arc<RwLock<AppState>>,
+    clients: &Arc<UpstreamClients>,
+    lb_metrics: &Arc<LbMetrics>,
+) -> Result<(), String> {
+    let (added, removed) = state.metrics.reconcile(backends)?;
+    for backend in &removed {
+        info!(
+            backend = %backend.name,
+            in_flight = backend.in_flight(),
+            "backend removed from the pool, letting its in-flight requests finish"
+        );
+        if state
+            .metrics
+            .backends
+            .iter()
+            .all(|b| b.name != backend.name)
+        {
+            let _ = lb_metrics
+                .backend_kv_ratio
+                .remove_label_values(&[&backend.name]);
+            let _ = lb_metrics
+                .backend_pressure
+                .remove_label_values(&[&backend.name]);
+            for quantile in LATENCY_QUANTILES {
+                let _ = lb_metrics
+                    .backend_latency_seconds
+                    .remove_label_values(&[&backend.name, quantile]);
+            }
+        }
+    }
+    for &id in &added {
+        if let Some(backend) = state.metrics.get(id) {
+            info!(backend = %backend.name, base_uri = %backend.base_uri, "backend added to the pool");
+        }
+        spawn_backend_tasks(app_state, clients, lb_metrics, id);
+    }
+    Ok(())
+}
+
+/// The value of `name` in the request's query string, as sent (not percent-decoded).
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Builds a response with a small JSON body.
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body.to_string()));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+/// Body of every error the load balancer answers itself: a stable machine-readable `error`
+/// code, a human-readable `message`, the request's `X-Request-Id` and any fields specific to
+/// the error, such as `retry_after_secs`.
+#[derive(Debug, Clone, Serialize)]
+struct ErrorResponse {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    details: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Builds the `ErrorResponse` response for `error`. `details` is a JSON object of extra
+/// fields, or null. The request ID is filled in by `handle_request`, which knows it.
+fn error_response(
+    status: StatusCode,
+    error: &'static str,
+    message: impl Into<String>,
+    details: serde_json::Value,
+) -> Response<Body> {
+    let details = match details {
+        serde_json::Value::Object(details) => details,
+        _ => serde_json::Map::new(),
+    };
+    let body = ErrorResponse {
+        error,
+        message: message.into(),
+        request_id: None,
+        details,
+    };
+    let mut resp = json_response(status, serde_json::to_value(&body).unwrap_or_default());
+    resp.extensions_mut().insert(body);
+    resp
+}
+
+/// Adds `request_id` to the body of a response built by `error_response`.
+fn add_request_id(resp: &mut Response<Body>, request_id: &HeaderValue) {
+    if let Some(mut body) = resp.extensions_mut().remove::<ErrorResponse>() {
+        body.request_id = request_id.to_str().ok().map(str::to_string);
+        if let Ok(bytes) = serde_json::to_vec(&body) {
+            *resp.body_mut() = Body::from(bytes);
+        }
+    }
+}
+
+/// Response extension naming the backend the request was last sent to.
+#[derive(Clone)]
+struct RoutedTo {
+    backend: String,
+    /// Backends the request was sent to, counting the last one.
+    attempts: usize,
+}
+
+/// One access log line, serialized as JSON with the fields in this order.
+#[derive(Serialize)]
+struct AccessLogEntry<'a> {
+    /// Seconds since the Unix epoch, with millisecond precision.
+    timestamp: f64,
+    client_ip: String,
+    method: &'a str,
+    path: &'a str,
+    backend: Option<&'a str>,
+    status: u16,
+    duration_ms: f64,
+    request_id: Option<&'a str>,
+}
+
+/// Which port a request arrived on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Listener {
+    /// `listen_addr`: proxies, and serves the built-in endpoints too unless `admin_listener`
+    /// is set.
+    Main,
+    /// `admin_listen_addr`: the built-in and admin endpoints only.
+    Admin,
+}
+
+/// Answers the load balancer's own endpoints and forwards everything else to a backend.
+async fn handle_request(
+    mut req: Request<Body>,
+    conn_info: ConnInfo,
+    listener: Listener,
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+) -> Result<Response<Body>, hyper::Error> {
+    let (access_log, admin_listener, cors_origin, preflight) = {
+        let state = app_state.read().await;
+        let origin = cors::allowed_origin(&state.config, req.headers());
+        let cors_enabled = !state.config.cors_allowed_origins.is_empty();
+        let preflight = if cors_enabled && cors::is_preflight(&req) {
+            Some(cors::preflight_response(&state.config, origin.clone()))
+        } else {
+            None
+        };
+        (
+            state.config.access_log,
+            state.config.admin_listener,
+            origin,
+            preflight,
+        )
+    };
+    if listener == Listener::Admin || !admin_listener {
+        let client_request_id = req.headers().get(&X_REQUEST_ID).cloned();
+        if let Some(mut resp) =
+            handle_builtin(&mut req, listener, &app_state, &clients, &lb_metrics).await
+        {
+            if let Some(request_id) = &client_request_id {
+                add_request_id(&mut resp, request_id);
+            }
+            return Ok(resp);
+        }
+    }
+    if listener == Listener::Admin {
+        return Ok(not_found());
+    }
+    // Preflight checks are the load balancer's to answer; backends never see them.
+    if let Some(resp) = preflight {
+        return Ok(resp);
+    }
+
+    let started = Instant::now();
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+
+    // Correlate the request across the LB and the backend, keeping the client's ID if it sent one.
+    let request_id = match req.headers().get(&X_REQUEST_ID) {
+        Some(id) => id.clone(),
+        None => {
+            let id = HeaderValue::from_str(&Uuid::new_v4().to_string())
+                .unwrap_or_else(|_| HeaderValue::from_static("unknown"));
+            req.headers_mut().insert(X_REQUEST_ID, id.clone());
+            id
+        }
+    };
+    let mut resp = route_request(req, conn_info, app_state, clients, lb_metrics).await?;
+    if access_log {
+        let entry = AccessLogEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| (d.as_millis() as f64) / 1000.0),
+            client_ip: conn_info.remote_addr.ip().to_string(),
+            method: method.as_str(),
+            path: &path,
+            backend: resp
+                .extensions()
+                .get::<RoutedTo>()
+                .map(|b| b.backend.as_str()),
+            status: resp.status().as_u16(),
+            duration_ms: (started.elapsed().as_micros() as f64) / 1000.0,
+            request_id: request_id.to_str().ok(),
+        };
+        if let Ok(line) = serde_json::to_string(&entry) {
+            info!(target: "access_log", "{}", line);
+        }
+    }
+    add_request_id(&mut resp, &request_id);
+    resp.headers_mut().insert(X_REQUEST_ID, request_id);
+    if let Some(origin) = cors_origin {
+        cors::add_cors_headers(resp.headers_mut(), origin);
+    }
+    Ok(resp)
+}
+
+/// The built-in endpoints: health, readiness, metrics and the admin API.
+async fn handle_builtin(
+    req: &mut Request<Body>,
+    listener: Listener,
+    app_state: &Arc<RwLock<AppState>>,
+    clients: &Arc<UpstreamClients>,
+    lb_metrics: &Arc<LbMetrics>,
+) -> Option<Response<Body>> {
+    if req.method() == Method::GET {
+        match req.uri().path() {
+            // Liveness: the process is up and serving.
+            "/healthz" => return Some(Response::new(Body::from("ok"))),
+            // Readiness: there is at least one backend we could route to.
+            "/readyz" => {
+                let state = app_state.read().await;
+                let resp = if state.metrics.backends.iter().any(Backend::available) {
+                    Response::new(Body::from("ready"))
+                } else {
+                    let mut resp = Response::new(Body::from("no backend online"));
+                    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    resp
+                };
+                return Some(resp);
+            }
+            "/metrics" => {
+                refresh_latency_gauges(&*app_state.read().await, lb_metrics);
+                let mut resp = Response::new(Body::from(lb_metrics.render()));
+                resp.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                return Some(resp);
+            }
+            _ => {}
+        }
+    }
+    if req.uri().path().starts_with("/admin/") {
+        return handle_admin(req, listener, app_state, clients, lb_metrics).await;
+    }
+    None
+}
+
+/// Serves the operator endpoints under `/admin/`. On the main port they need an admin token
+/// and `None` is returned without one, so the request is proxied like any other; the admin
+/// listener serves them without a token unless one is configured.
+async fn handle_admin(
+    req: &mut Request<Body>,
+    listener: Listener,
+    app_state: &Arc<RwLock<AppState>>,
+    clients: &Arc<UpstreamClients>,
+    lb_metrics: &Arc<LbMetrics>,
+) -> Option<Response<Body>> {
+    let authorized = {
+        let state = app_state.read().await;
+        match state.config.admin_token.as_deref() {
+            Some(expected) => req
+                .headers()
+                .get(&X_ADMIN_TOKEN)
+                .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())),
+            None if listener == Listener::Admin => true,
+            None => return None,
+        }
+    };
+    if !authorized {
+        warn!(
+            path = req.uri().path(),
+            status = 401,
+            "rejected admin request"
+        );
+        return Some(error_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "admin endpoints need a valid X-Admin-Token header",
+            serde_json::Value::Null,
+        ));
+    }
+
+    let path = req.uri().path().to_string();
+    let resp = match (req.method(), path.as_str()) {
+        (&Method::POST, "/admin/reload") => {
+            info!("reload requested over the admin API");
+            match reload_config(app_state, clients, lb_metrics).await {
+                Ok(summary) => json_response(StatusCode::OK, summary),
+                Err(e) => {
+                    error!(error = %e, status = 400, "config reload failed, keeping the current configuration");
+                    error_response(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_config",
+                        e,
+                        serde_json::Value::Null,
+                    )
+                }
+            }
+        }
+        (&Method::GET, "/admin/config") => {
+            let state = app_state.read().await;
+            match redacted_config(&state.config) {
+                Ok(config) => json_response(StatusCode::OK, config),
+                Err(e) => {
+                    error!(error = %e, status = 500, "failed to serialize the configuration");
+                    error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal_error",
+                        "failed to serialize the configuration",
+                        serde_json::Value::Null,
+                    )
+                }
+            }
+        }
+        (&Method::GET, "/admin/top-clients") => {
+            let limit =
+                query_param(req, "limit").map_or(Ok(DEFAULT_TOP_CLIENTS), str::parse::<usize>);
+            match limit {
+                Ok(limit) => {
+                    let state = app_state.read().await;
+                    let counts = lock(&state.client_counts);
+                    let clients: Vec<_> = counts
+                        .top(limit, Instant::now())
+                        .into_iter()
+                        .map(|(ip, requests)| json!({ "ip": ip.to_string(), "requests": requests }))
+                        .collect();
+                    json_response(
+                        StatusCode::OK,
+                        json!({ "window_secs": counts.window.as_secs(), "clients": clients }),
+                    )
+                }
+                Err(_) => error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_query",
+                    "limit must be a non-negative integer",
+                    serde_json::Value::Null,
+                ),
+            }
+        }
+        (&Method::GET, "/admin/recommend") => {
+            let state = app_state.read().await;
+            let path = query_param(req, "path").unwrap_or("/");
+            let pool =
+                query_param(req, "pool").unwrap_or_else(|| state.config.pool_for(path, None));
+            if !state.metrics.backends.iter().any(|b| b.pool == pool) {
+                return Some(error_response(
+                    StatusCode::NOT_FOUND,
+                    "unknown_pool",
+                    format!("no backend serves pool {:?}", pool),
+                    json!({ "pool": pool }),
+                ));
+            }
+            match routing::recommend(&state, pool, path) {
+                Some(backend) => json_response(
+                    StatusCode::OK,
+                    json!({
+                        "pool": pool,
+                        "backend": backend.name,
+                        "kv_ratio": backend.kv_ratio,
+                        "pressure": backend.pressure,
+                    }),
+                ),
+                None => error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "no_backend_available",
+                    "no backend in this pool could take a request right now",
+                    json!({ "pool": pool }),
+                ),
+            }
+        }
+        (&Method::GET, "/admin/connections") => {
+            let connections = app_state.read().await.connections.clone();
+            json_response(
+                StatusCode::OK,
+                json!({ "active": connections.active(), "max_connections": connections.max }),
+            )
+        }
+        (&Method::GET, "/admin/backends") => {
+            let state = app_state.read().await;
+            let now = Instant::now();
+            let latency_window = Duration::from_secs(state.config.latency_window_secs);
+            let backends: Vec<_> = state
+                .metrics
+                .backends
+                .iter()
+                .map(|backend| {
+                    json!({
+                        "name": backend.name,
+                        "base_uri": backend.base_uri.to_string(),
+                        "pool": backend.pool,
+                        "weight": backend.weight,
+                        "online": backend.online,
+                        "healthy": backend.healthy,
+                        "kv_ratio": backend.kv_ratio,
+                        "kv_max_blocks": backend.kv_max_blocks,
+                        "kv_free_blocks": backend.kv_free_blocks,
+                        "pressure": backend.pressure,
+                        "raw_pressure": backend.raw_pressure,
+                        "awaiting_metrics": backend.awaiting_metrics(),
+                        "shedding": backend.shedding,
+                        "stale": backend.stale,
+                        "metrics_age_secs": backend
+                            .reports_load()
+                            .then(|| now.saturating_duration_since(backend.last_updated).as_secs_f64()),
+                        "drained": backend.drained,
+                        "backoff_secs": backend
+                            .backoff_until
+                            .filter(|&until| until > now)
+                            .map(|until| (until - now).as_secs_f64()),
+                        "injected_failure": backend
+                            .injected_failure
+                            .filter(|failure| failure.until > now)
+                            .map(|failure| json!({
+                                "failure_rate": failure.rate,
+                                "remaining_secs": (failure.until - now).as_secs_f64(),
+                            })),
+                        "in_flight": backend.in_flight(),
+                        "max_concurrency": backend.max_concurrency,
+                        "circuit_breaker": lock(&backend.breaker).state.as_str(),
+                        "latency_ms": lock(&backend.latency)
+                            .percentiles(now, latency_window)
+                            .map(|[p50, p95, p99]| json!({ "p50": p50 * 1e3, "p95": p95 * 1e3, "p99": p99 * 1e3 })),
+                    })
+                })
+                .collect();
+            json_response(StatusCode::OK, json!({ "backends": backends }))
+        }
+        (&Method::POST, _) if path.starts_with("/admin/metrics/") => {
+            let name = &path["/admin/metrics/".len()..];
+            let body = std::mem::take(req.body_mut());
+            push_metrics(app_state, lb_metrics, name, body).await
+        }
+        (&Method::POST, _)
+            if path.starts_with("/admin/backends/") && path.ends_with("/inject_failure") =>
+        {
+            let name = &path["/admin/backends/".len()..path.len() - "/inject_failure".len()];
+            let body = std::mem::take(req.body_mut());
+            inject_failure(app_state, name, body).await
+        }
+        (&Method::POST, _) if path.starts_with("/admin/backends/") => {
+            let action =
+                path["/admin/backends/".len()..]
+                    .rsplit_once('/')
+                    .and_then(|(name, action)| match action {
+                        "drain" => Some((name, true)),
+                        "enable" => Some((name, false)),
+                        _ => None,
+                    });
+            match action {
+                Some((name, drained)) => set_drained(app_state, name, drained).await,
+                None => not_found(),
+            }
+        }
+        _ => not_found(),
+    };
+    Some(resp)
+}
+
+fn not_found() -> Response<Body> {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        "no such endpoint",
+        serde_json::Value::Null,
+    )
+}
+
+fn unknown_backend(name: &str) -> Response<Body> {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "unknown_backend",
+        format!("no backend is named {:?}", name),
+        json!({ "backend": name }),
+    )
+}
+
+/// Takes a backend out of rotation (or puts it back) without touching its polling or health checks.
+async fn set_drained(
+    app_state: &Arc<RwLock<AppState>>,
+    name: &str,
+    drained: bool,
+) -> Response<Body> {
+    let mut state = app_state.write().await;
+    let backend = match state.metrics.backends.iter_mut().find(|b| b.name == name) {
+        Some(backend) => backend,
+        None => return unknown_backend(name),
+    };
+    if backend.drained != drained {
+        backend.drained = drained;
+        if drained {
+            info!(backend = %name, in_flight = backend.in_flight(), "backend drained by operator");
+        } else {
+            info!(backend = %name, "backend enabled by operator");
+        }
+    }
+    json_response(
+        StatusCode::OK,
+        json!({ "backend": name, "drained": drained }),
+    )
+}
+
+/// Body of `POST /admin/backends/{name}/inject_failure`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FailureInjection {
+    /// How long the failures last; 0 stops them.
+    duration_secs: f64,
+    /// Share of the backend's requests that fail meanwhile.
+    #[serde(default = "always")]
+    failure_rate: f64,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+/// Makes a backend's requests fail for a while without them reaching it, when `chaos_enabled`.
+async fn inject_failure(
+    app_state: &Arc<RwLock<AppState>>,
+    name: &str,
+    body: Body,
+) -> Response<Body> {
+    let invalid = |message: String| {
+        error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_payload",
+            message,
+            serde_json::Value::Null,
+        )
+    };
+    let bytes = match read_body_limited(body, MAX_PUSH_BODY_BYTES).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return payload_too_large(MAX_PUSH_BODY_BYTES),
+        Err(e) => return invalid(format!("failed to read the body: {}", e)),
+    };
+    let injection: FailureInjection = match serde_json::from_slice(&bytes) {
+        Ok(injection) => injection,
+        Err(e) => return invalid(e.to_string()),
+    };
+    if !(injection.duration_secs.is_finite() && injection.duration_secs >= 0.0) {
+        return invalid("duration_secs must be a non-negative number".to_string());
+    }
+    if !(0.0..=1.0).contains(&injection.failure_rate) {
+        return invalid("failure_rate must be within 0.0..=1.0".to_string());
+    }
+    let mut state = app_state.write().await;
+    if !state.config.chaos_enabled {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "chaos_disabled",
+            "failure injection needs chaos_enabled in the configuration",
+            serde_json::Value::Null,
+        );
+    }
+    let backend = match state.metrics.backends.iter_mut().find(|b| b.name == name) {
+        Some(backend) => backend,
+        None => return unknown_backend(name),
+    };
+    let duration = Duration::from_secs_f64(injection.duration_secs);
+    backend.injected_failure = (!duration.is_zero()).then(|| InjectedFailure {
+        until: Instant::now() + duration,
+        rate: injection.failure_rate,
+    });
+    warn!(
+        backend = %name,
+        duration_secs = injection.duration_secs,
+        failure_rate = injection.failure_rate,
+        "failure injection set by operator"
+    );
+    json_response(
+        StatusCode::OK,
+        json!({
+            "backend": name,
+            "duration_secs": injection.duration_secs,
+            "failure_rate": injection.failure_rate,
+        }),
+    )
+}
+
+/// KV cache stats a backend pushes instead of waiting to be polled.
+#[derive(Deserialize)]
+struct PushedLoad {
+    used: f64,
+    max: f64,
+}
+
+/// Applies KV stats pushed by a backend, which pause its polling for `metrics_push_ttl_secs`.
+async fn push_metrics(
+    app_state: &Arc<RwLock<AppState>>,
+    lb_metrics: &LbMetrics,
+    name: &str,
+    body: Body,
+) -> Response<Body> {
+    let invalid = |message: String| {
+        error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_payload",
+            message,
+            serde_json::Value::Null,
+        )
+    };
+    let bytes = match read_body_limited(body, MAX_PUSH_BODY_BYTES).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return payload_too_large(MAX_PUSH_BODY_BYTES),
+        Err(e) => return invalid(format!("failed to read the body: {}", e)),
+    };
+    let load: PushedLoad = match serde_json::from_slice(&bytes) {
+        Ok(load) => load,
+        Err(e) => return invalid(e.to_string()),
+    };
+    if !(load.max > 0.0 && (0.0..=load.max).contains(&load.used)) {
+        return invalid("used must be within 0..=max, and max above 0".to_string());
+    }
+    let mut state = app_state.write().await;
+    let id = match state.metrics.backends.iter().find(|b| b.name == name) {
+        Some(backend) => backend.id,
+        None => return unknown_backend(name),
+    };
+    let ratio = load.used / load.max;
+    // A push carries only the KV cache, so it is the whole pressure score.
+    // Pushed stats are taken as they are: the backend sends them because they just changed.
+    record_load(&mut state, lb_metrics, id, load.used, load.max, ratio, 1.0);
+    if let Some(backend) = state.metrics.get_mut(id) {
+        backend.last_pushed = Some(Instant::now());
+    }
+    debug!(backend = %name, used = load.used, max = load.max, kv_ratio = ratio, "KV cache pushed");
+    json_response(
+        StatusCode::OK,
+        json!({ "backend": name, "kv_ratio": ratio }),
+    )
+}
+
+/// Compares secrets without returning early on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// What the request handlers need to know about the client's connection.
+#[derive(Clone, Copy)]
+struct ConnInfo {
+    remote_addr: SocketAddr,
+    /// `http` or `https`, as reported in `X-Forwarded-Proto`.
+    proto: &'static str,
+}
+
+/// A connection accepted by the server, either plain TCP or TLS on top of it.
+trait ClientConnection {
+    fn conn_info(&self) -> ConnInfo;
+}
+
+impl ClientConnection for AddrStream {
+    fn conn_info(&self) -> ConnInfo {
+        ConnInfo {
+            remote_addr: self.remote_addr(),
+            proto: "http",
+        }
+    }
+}
+
+impl ClientConnection for TlsStream<AddrStream> {
+    fn conn_info(&self) -> ConnInfo {
+        ConnInfo {
+            remote_addr: self.get_ref().0.remote_addr(),
+            proto: "https",
+        }
+    }
+}
+
+/// Serves connections from `incoming` until `stop` fires, then drains them gracefully.
+async fn serve<I>(
+    incoming: I,
+    listener: Listener,
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+    connections: Option<Arc<Connections>>,
+    stop: oneshot::Receiver<()>,
+) -> hyper::Result<()>
+where
+    I: Accept,
+    I::Conn: ClientConnection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_svc = make_service_fn(move |conn: &I::Conn| {
+        let conn_info = conn.conn_info();
+        let app_state = app_state.clone();
+        let clients = clients.clone();
+        let lb_metrics = lb_metrics.clone();
+        let guard = connections
+            .as_ref()
+            .map(|connections| (connections.open(), connections.max));
+        async move {
+            // Failing here makes hyper close the connection right away.
+            let guard = match guard {
+                Some((None, max)) => {
+                    debug!(client = %conn_info.remote_addr, max_connections = max, "connection limit reached, closing");
+                    return Err(io::Error::other("max_connections reached"));
+                }
+                Some((guard, _)) => guard,
+                None => None,
+            };
+            Ok(service_fn(move |req| {
+                // The service lives as long as the connection, and with it the guard.
+                let _connection = &guard;
+                handle_request(
+                    req,
+                    conn_info,
+                    listener,
+                    app_state.clone(),
+                    clients.clone(),
+                    lb_metrics.clone(),
+                )
+            }))
+        }
+    });
+
+    Server::builder(incoming)
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            let _ = stop.await;
+        })
+        .await
+}
+
+/// Loads the PEM certificate chain and private key used to terminate TLS.
+fn load_tls_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, String> {
+    let (certs, key) = load_cert_and_key(cert_path, key_path)?;
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Reads a PEM certificate chain and the first private key found in `key_path`.
+fn load_cert_and_key(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    let cert_file =
+        File::open(cert_path).map_err(|e| format!("failed to open {}: {}", cert_path, e))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .map_err(|e| format!("failed to read certificates from {}: {}", cert_path, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_path));
+    }
+
+    let key_file =
+        File::open(key_path).map_err(|e| format!("failed to open {}: {}", key_path, e))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .map_err(|e| format!("failed to read private key from {}: {}", key_path, e))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key found in {}", key_path))?;
+    Ok((certs, key))
+}
+
+/// Wraps plain TCP connections in TLS. Handshakes run in their own tasks so a slow client
+/// can't hold up the accept loop; finished streams are handed to hyper as they complete.
+fn tls_incoming(
+    mut incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+) -> impl Accept<Conn = TlsStream<AddrStream>, Error = io::Error> {
+    let (tx, rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        loop {
+            let conn = match poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await {
+                Some(Ok(conn)) => conn,
+                Some(Err(e)) => {
+                    warn!(error = %e, "failed to accept connection");
+                    continue;
+                }
+                None => return,
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(e)) => debug!(error = %e, "TLS handshake failed"),
+                    Err(_) => debug!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+    accept::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|conn| (Ok(conn), rx))
+    }))
+}
+
+/// A load balancer started by `run`. It keeps serving until `shutdown`, or until it fails.
+pub struct RunningServer {
+    addr: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+    in_flight: IntGauge,
+    drain_timeout: Duration,
+    stop_tx: oneshot::Sender<()>,
+    admin_stop_tx: Option<oneshot::Sender<()>>,
+    server_task: JoinHandle<Result<(), hyper::Error>>,
+}
+
+impl RunningServer {
+    /// Where the main listener is bound; the actual port when `listen_addr` asked for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Where the admin listener is bound, if `admin_listener` is set.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// Resolves only if the server stops on its own, with the reason. The server can't be
+    /// shut down afterwards.
+    pub async fn failed(&mut self) -> String {
+        match (&mut self.server_task).await {
+            Ok(Err(e)) => format!("server error: {}", e),
+            Err(e) => format!("server task failed: {}", e),
+            Ok(Ok(())) => "server stopped".to_string(),
+        }
+    }
+
+    /// Stops accepting connections and gives the in-flight requests up to
+    /// `shutdown_drain_timeout_secs` to finish.
+    pub async fn shutdown(self) {
+        let draining = self.in_flight.get();
+        info!(in_flight = draining, "shutting down, draining");
+        let _ = self.stop_tx.send(());
+        if let Some(admin_stop_tx) = self.admin_stop_tx {
+            let _ = admin_stop_tx.send(());
+        }
+        match timeout(self.drain_timeout, self.server_task).await {
+            Ok(Ok(Ok(()))) => info!(drained = draining, "drained in-flight requests, exiting"),
+            Ok(Ok(Err(e))) => error!(error = %e, "server error while draining"),
+            Ok(Err(e)) => error!(error = %e, "server task failed while draining"),
+            Err(_) => warn!(
+                remaining = self.in_flight.get(),
+                "drain timeout elapsed, exiting with requests still in flight"
+            ),
+        }
+    }
+}
+
+/// Binds the listeners, starts polling the backends and serves requests in the background.
+/// The config is reloaded from `LB_CONFIG` on SIGHUP.
+pub async fn run(config: LbConfig) -> Result<RunningServer, String> {
+    start(config, None).await
+}
+
+/// Like `run`, but routes with `selector` instead of `routing_strategy`. Sticky sessions,
+/// `X-Force-Backend`, failover and the warmup ramp still apply around it.
+pub async fn run_with_selector(
+    config: LbConfig,
+    selector: Arc<dyn Selector>,
+) -> Result<RunningServer, String> {
+    start(config, Some(selector)).await
+}
+
+async fn start(
+    config: LbConfig,
+    selector: Option<Arc<dyn Selector>>,
+) -> Result<RunningServer, String> {
+    info!(
+        capacity_threshold = config.capacity_threshold,
+        release_threshold = config.release_threshold,
+        routing_strategy = config.routing_strategy.as_str(),
+        "loaded configuration"
+    );
+    for backend in &config.backends {
+        info!(backend = %backend.name, base_uri = %backend.base_uri, "configured backend");
+    }
+
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(
+            load_tls_config(cert_path, key_path)
+                .map_err(|e| format!("failed to load TLS certificate: {}", e))?,
+        ),
+        _ => None,
+    };
+    let drain_timeout = Duration::from_secs(config.shutdown_drain_timeout_secs);
+    let addr = config.listen_addr;
+    let admin_addr = if config.admin_listener {
+        Some(config.admin_listen_addr)
+    } else {
+        None
+    };
+    if config.upstream_tls_skip_verify {
+        warn!("upstream_tls_skip_verify is set, certificates of https backends are not verified");
+    }
+    let clients = Arc::new(
+        UpstreamClients::new(&config)
+            .map_err(|e| format!("failed to set up upstream TLS: {}", e))?,
+    );
+    // Startup only waits for the self-test when its result can stop it.
+    if config.require_backend_on_startup && !config.backends.is_empty() {
+        if self_test(&config, &clients).await == 0 {
+            return Err(
+                "none of the backends is reachable and require_backend_on_startup is set"
+                    .to_string(),
+            );
+        }
+    } else {
+        let (config, clients) = (config.clone(), clients.clone());
+        tokio::spawn(async move { self_test(&config, &clients).await });
+    }
+    let app_state = Arc::new(RwLock::new(AppState::new(config, selector)?));
+    let lb_metrics = Arc::new(LbMetrics::new());
+    let in_flight = lb_metrics.requests_in_flight.clone();
+
+    let ids: Vec<u64> = app_state
+        .read()
+        .await
+        .metrics
+        .backends
+        .iter()
+        .map(|b| b.id)
+        .collect();
+    for id in ids {
+        spawn_backend_tasks(&app_state, &clients, &lb_metrics, id);
+    }
+    tokio::spawn(reload_on_sighup(
+        app_state.clone(),
+        clients.clone(),
+        lb_metrics.clone(),
+    ));
+    tokio::spawn(staleness_loop(app_state.clone()));
+    tokio::spawn(discovery::discovery_loop(
+        app_state.clone(),
+        clients.clone(),
+        lb_metrics.clone(),
+    ));
+
+    let incoming = AddrIncoming::bind(&addr)
+        .map_err(|e| format!("failed to bind listen address {}: {}", addr, e))?;
+    // Report what we actually bound, which differs from `addr` when binding port 0.
+    let addr = incoming.local_addr();
+
+    // The admin listener is always plain HTTP; it is meant to be bound to loopback or a
+    // management network.
+    let mut admin_stop_tx = None;
+    let mut bound_admin_addr = None;
+    if let Some(admin_addr) = admin_addr {
+        let admin_incoming = AddrIncoming::bind(&admin_addr)
+            .map_err(|e| format!("failed to bind admin listen address {}: {}", admin_addr, e))?;
+        bound_admin_addr = Some(admin_incoming.local_addr());
+        info!(
+            "admin endpoints listening on http://{}",
+            admin_incoming.local_addr()
+        );
+        let (stop_tx, admin_stop_rx) = oneshot::channel::<()>();
+        let admin_server = serve(
+            admin_incoming,
+            Listener::Admin,
+            app_state.clone(),
+            clients.clone(),
+            lb_metrics.clone(),
+            None,
+            admin_stop_rx,
+        );
+        tokio::spawn(async move {
+            if let Err(e) = admin_server.await {
+                error!(error = %e, "admin server error");
+            }
+        });
+        admin_stop_tx = Some(stop_tx);
+    }
+
+    let connections = app_state.read().await.connections.clone();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server_task = match tls_config {
+        Some(tls_config) => {
+            info!("Rust load balancer listening on https://{}", addr);
+            let incoming = tls_incoming(incoming, TlsAcceptor::from(Arc::new(tls_config)));
+            tokio::spawn(serve(
+                incoming,
+                Listener::Main,
+                app_state,
+                clients,
+                lb_metrics,
+                Some(connections),
+                stop_rx,
+            ))
+        }
+        None => {
+            info!("Rust load balancer listening on http://{}", addr);
+            tokio::spawn(serve(
+                incoming,
+                Listener::Main,
+                app_state,
+                clients,
+                lb_metrics,
+                Some(connections),
+                stop_rx,
+            ))
+        }
+    };
+
+    Ok(RunningServer {
+        addr,
+        admin_addr: bound_admin_addr,
+        in_flight,
+        drain_timeout,
+        stop_tx,
+        admin_stop_tx,
+        server_task,
+    })
+}
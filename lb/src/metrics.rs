@@ -0,0 +1,1584 @@
+//! The backends' runtime state, and the loops that keep it up to date: KV cache scrapes,
+//! health checks and staleness.
+
+use hyper::header::USER_AGENT;
+use hyper::{Body, Request, StatusCode, Uri};
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, info, warn};
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{
+    health_check_uri, parse_absolute_uri, BackendConfig, BackendProtocol, KvMetricsLabels,
+    LbConfig, MetricsFormat, PressureMetricConfig, Thresholds,
+};
+use crate::lb_metrics::LbMetrics;
+use crate::selector::BackendView;
+use crate::upstream::{UpstreamClient, UpstreamClients};
+use crate::{lock, AppState};
+
+/// A backend server together with the latest metrics we have for it.
+pub(crate) struct Backend {
+    /// Stable identity for the backend's background tasks; indices shift when the config is
+    /// reloaded.
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    pub(crate) pool: String,
+    pub(crate) weight: u32,
+    pub(crate) protocol: BackendProtocol,
+    pub(crate) base_uri: Uri,
+    pub(crate) kv_metrics_url: Option<String>,
+    pub(crate) metrics_format: MetricsFormat,
+    pub(crate) kv_ratio: f64,
+    /// Total and unused KV cache blocks from the last scrape; both 0 while unknown.
+    pub(crate) kv_max_blocks: f64,
+    pub(crate) kv_free_blocks: f64,
+    /// Blend of the KV ratio and the `pressure_metrics`, from 0 (idle) to 1, smoothed over the
+    /// scrapes by `kv_smoothing_factor`; routing compares backends by this. Equals `kv_ratio`
+    /// when no extra metrics are configured and smoothing is off.
+    pub(crate) pressure: f64,
+    /// The pressure of the last sample alone; None until the first one.
+    pub(crate) raw_pressure: Option<f64>,
+    /// Whether the last metrics scrape reached the backend.
+    pub(crate) online: bool,
+    pub(crate) health_check_uri: Option<Uri>,
+    /// Result of the active health checks, with hysteresis from the consecutive counters.
+    pub(crate) healthy: bool,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    /// Requests forwarded to this backend whose response hasn't finished streaming yet.
+    pub(crate) in_flight: Arc<AtomicUsize>,
+    /// The part of `in_flight` that failed over from another backend.
+    pub(crate) failover_in_flight: Arc<AtomicUsize>,
+    /// Permits for `max_concurrency`, held until the response has finished streaming.
+    pub(crate) max_concurrency: Option<usize>,
+    pub(crate) concurrency: Option<Arc<Semaphore>>,
+    pub(crate) breaker: Arc<Mutex<CircuitBreaker>>,
+    pub(crate) latency: Arc<Mutex<LatencyHistogram>>,
+    /// Set by an operator through the admin API to stop routing here; polling carries on.
+    pub(crate) drained: bool,
+    /// Set from the `Retry-After` of a 429 or 503 the backend answered with; routing skips the
+    /// backend until then.
+    pub(crate) backoff_until: Option<Instant>,
+    /// Failures injected through the admin API while `chaos_enabled` is set.
+    pub(crate) injected_failure: Option<InjectedFailure>,
+    /// Last KV stats pushed through `POST /admin/metrics/{name}`; polling pauses while recent.
+    pub(crate) last_pushed: Option<Instant>,
+    /// Last successful KV cache scrape or push, or when the backend was added.
+    pub(crate) last_updated: Instant,
+    /// Set when `last_updated` is older than `staleness_secs`; the pressure is then unknown and
+    /// routing treats the backend as full.
+    pub(crate) stale: bool,
+    /// When the backend last came back online or healthy, and how long its warmup lasts.
+    warmup: Option<(Instant, Duration)>,
+    /// Set once the ratio reaches the capacity threshold and cleared only when it falls below
+    /// the release threshold, so routing doesn't flap while the ratio hovers around one value.
+    pub(crate) shedding: bool,
+}
+
+impl Backend {
+    /// Fails if `base_uri` or `health_path` don't make a URI, which validation already rules out.
+    pub(crate) fn new(id: u64, config: &BackendConfig) -> Result<Self, String> {
+        let invalid = |field: &str, e: String| {
+            format!("backend {:?} has an invalid {}: {}", config.name, field, e)
+        };
+        let base_uri = parse_absolute_uri(&config.base_uri).map_err(|e| invalid("base_uri", e))?;
+        let health_check_uri = match &config.health_path {
+            Some(path) => Some(
+                health_check_uri(&config.base_uri, path).map_err(|e| invalid("health_path", e))?,
+            ),
+            None => None,
+        };
+        Ok(Backend {
+            id,
+            name: config.name.clone(),
+            pool: config.pool().to_string(),
+            weight: config.weight.unwrap_or(1),
+            protocol: config.protocol.unwrap_or(BackendProtocol::Http1),
+            base_uri,
+            kv_metrics_url: config.kv_metrics_url.clone(),
+            metrics_format: config
+                .metrics_format
+                .unwrap_or(MetricsFormat::PrometheusText),
+            kv_ratio: 0.0,
+            kv_max_blocks: 0.0,
+            kv_free_blocks: 0.0,
+            pressure: 0.0,
+            raw_pressure: None,
+            online: true,
+            health_check_uri,
+            healthy: true,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            failover_in_flight: Arc::new(AtomicUsize::new(0)),
+            max_concurrency: config.max_concurrency,
+            concurrency: config
+                .max_concurrency
+                .map(|max| Arc::new(Semaphore::new(max))),
+            breaker: Arc::new(Mutex::new(CircuitBreaker::new())),
+            latency: Arc::new(Mutex::new(LatencyHistogram::new())),
+            drained: false,
+            backoff_until: None,
+            injected_failure: None,
+            last_pushed: None,
+            last_updated: Instant::now(),
+            stale: false,
+            warmup: None,
+            shedding: false,
+        })
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The snapshot a `Selector` sees; `eligible` is false for backends this request may not
+    /// go to anyway, e.g. because it already failed there.
+    pub(crate) fn view(&self, eligible: bool) -> BackendView<'_> {
+        BackendView {
+            name: &self.name,
+            available: eligible && self.available() && self.has_capacity(),
+            weight: self.weight,
+            kv_ratio: self.kv_ratio,
+            pressure: self.load(),
+            shedding: self.shedding,
+            free_blocks: self.free_blocks(),
+            in_flight: self.in_flight(),
+            tier: None,
+        }
+    }
+
+    /// Whether the backend sheds load for a request whose priority moves its capacity threshold
+    /// by `offset`. A higher threshold lets the request through until the pressure reaches it,
+    /// a lower one sheds it from there on; the hysteresis only applies to the unshifted one.
+    pub(crate) fn sheds_for(&self, capacity_threshold: f64, offset: f64) -> bool {
+        let over = self.load() >= capacity_threshold + offset;
+        if offset > 0.0 {
+            self.shedding && (self.stale || over)
+        } else if offset < 0.0 {
+            self.shedding || over
+        } else {
+            self.shedding
+        }
+    }
+
+    /// Whether the backend may take another failed-over request under `max_failover_in_flight`.
+    pub(crate) fn takes_failover(&self, max_failover_in_flight: usize) -> bool {
+        max_failover_in_flight == 0
+            || self.failover_in_flight.load(Ordering::Relaxed) < max_failover_in_flight
+    }
+
+    /// Whether the backend is below its `max_concurrency`.
+    pub(crate) fn has_capacity(&self) -> bool {
+        self.concurrency
+            .as_ref()
+            .is_none_or(|permits| permits.available_permits() > 0)
+    }
+
+    fn same_endpoints(&self, other: &Backend) -> bool {
+        self.base_uri == other.base_uri
+            && self.protocol == other.protocol
+            && self.kv_metrics_url == other.kv_metrics_url
+            && self.metrics_format == other.metrics_format
+            && self.health_check_uri == other.health_check_uri
+    }
+
+    /// Whether the backend is scraped but hasn't reported its KV cache yet, so neither its load
+    /// nor whether it is reachable is known.
+    pub(crate) fn awaiting_metrics(&self) -> bool {
+        self.kv_metrics_url.is_some() && self.raw_pressure.is_none()
+    }
+
+    /// Whether requests may be routed to this backend.
+    pub(crate) fn available(&self) -> bool {
+        let now = Instant::now();
+        self.online
+            && self.healthy
+            && !self.drained
+            && self.backoff_until.is_none_or(|until| now >= until)
+            && lock(&self.breaker).allows_request(now)
+    }
+
+    /// The pressure routing compares: the scraped one, or full when it is stale.
+    pub(crate) fn load(&self) -> f64 {
+        if self.stale {
+            1.0
+        } else {
+            self.pressure
+        }
+    }
+
+    /// Free KV cache blocks routing can count on: none when the last scrape is stale.
+    pub(crate) fn free_blocks(&self) -> f64 {
+        if self.stale {
+            0.0
+        } else {
+            self.kv_free_blocks
+        }
+    }
+
+    /// Whether anything keeps the KV ratio up to date: a metrics endpoint, or pushes.
+    pub(crate) fn reports_load(&self) -> bool {
+        self.kv_metrics_url.is_some() || self.last_pushed.is_some()
+    }
+
+    /// Marks the backend online or offline, starting its warmup when it comes back.
+    fn set_online(&mut self, online: bool, warmup: Duration) {
+        if online && !self.online {
+            self.start_warmup(warmup);
+        }
+        self.online = online;
+    }
+
+    fn start_warmup(&mut self, warmup: Duration) {
+        self.warmup = if warmup.is_zero() {
+            None
+        } else {
+            Some((Instant::now(), warmup))
+        };
+    }
+
+    /// Fraction of its normal traffic the backend should get, ramping from 0 to 1 over the warmup.
+    pub(crate) fn ramp(&self, now: Instant) -> f64 {
+        match self.warmup {
+            Some((since, warmup)) => {
+                (now.saturating_duration_since(since).as_secs_f64() / warmup.as_secs_f64()).min(1.0)
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Records one health probe result. Returns true if the backend became healthy or unhealthy.
+    fn record_health_check(
+        &mut self,
+        ok: bool,
+        unhealthy_threshold: u32,
+        healthy_threshold: u32,
+    ) -> bool {
+        let was_healthy = self.healthy;
+        if ok {
+            self.consecutive_failures = 0;
+            self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+            if self.consecutive_successes >= healthy_threshold {
+                self.healthy = true;
+            }
+        } else {
+            self.consecutive_successes = 0;
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+            if self.consecutive_failures >= unhealthy_threshold {
+                self.healthy = false;
+            }
+        }
+        self.healthy != was_healthy
+    }
+
+    /// Records new KV block counts and pressure score, folds the pressure into the moving
+    /// average with weight `smoothing` and applies the shedding hysteresis to the result.
+    /// Returns true if the backend started or stopped shedding.
+    fn update_load(
+        &mut self,
+        used: f64,
+        max: f64,
+        pressure: f64,
+        smoothing: f64,
+        thresholds: Thresholds,
+    ) -> bool {
+        self.kv_ratio = used / max;
+        self.kv_max_blocks = max;
+        self.kv_free_blocks = (max - used).max(0.0);
+        self.pressure = ewma(
+            self.raw_pressure.map(|_| self.pressure),
+            pressure,
+            smoothing,
+        );
+        self.raw_pressure = Some(pressure);
+        let pressure = self.pressure;
+        self.last_updated = Instant::now();
+        self.stale = false;
+        let was_shedding = self.shedding;
+        if pressure >= thresholds.capacity {
+            self.shedding = true;
+        } else if pressure < thresholds.release {
+            self.shedding = false;
+        }
+        self.shedding != was_shedding
+    }
+}
+
+/// Requests to a backend that are answered with an error by the load balancer itself instead
+/// of being sent there.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct InjectedFailure {
+    pub(crate) until: Instant,
+    /// Share of the requests that fail, from 0 to 1.
+    pub(crate) rate: f64,
+}
+
+impl InjectedFailure {
+    /// Whether the request sent at `now` fails.
+    pub(crate) fn strikes<R: Rng + ?Sized>(&self, now: Instant, rng: &mut R) -> bool {
+        now < self.until && rng.gen_bool(self.rate)
+    }
+}
+
+pub(crate) struct MetricsState {
+    pub(crate) backends: Vec<Backend>,
+    /// Id for the next backend added, so ids stay unique across config reloads.
+    next_id: u64,
+}
+
+impl MetricsState {
+    pub(crate) fn new(backends: &[BackendConfig]) -> Result<Self, String> {
+        let mut state = MetricsState {
+            backends: Vec::new(),
+            next_id: 0,
+        };
+        for config in backends {
+            let backend = state.new_backend(config)?;
+            state.backends.push(backend);
+        }
+        Ok(state)
+    }
+
+    fn new_backend(&mut self, config: &BackendConfig) -> Result<Backend, String> {
+        let backend = Backend::new(self.next_id, config)?;
+        self.next_id += 1;
+        Ok(backend)
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<&Backend> {
+        self.backends.iter().find(|b| b.id == id)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: u64) -> Option<&mut Backend> {
+        self.backends.iter_mut().find(|b| b.id == id)
+    }
+
+    /// Replaces the backend list with `configs`. Backends whose name and endpoints are
+    /// unchanged keep their id and runtime state. Returns the ids of newly added backends,
+    /// which need their poll and health loops started, and the backends that were dropped. On
+    /// error the backend list is left as it was.
+    pub(crate) fn reconcile(
+        &mut self,
+        configs: &[BackendConfig],
+    ) -> Result<(Vec<u64>, Vec<Backend>), String> {
+        let candidates = configs
+            .iter()
+            .map(|config| self.new_backend(config))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut old = std::mem::take(&mut self.backends);
+        let mut added = Vec::new();
+        for candidate in candidates {
+            match old
+                .iter()
+                .position(|b| b.name == candidate.name && b.same_endpoints(&candidate))
+            {
+                Some(i) => {
+                    let mut backend = old.remove(i);
+                    backend.pool = candidate.pool;
+                    backend.weight = candidate.weight;
+                    if backend.max_concurrency != candidate.max_concurrency {
+                        // Requests already in flight hold permits of the old semaphore, so the
+                        // new cap is briefly exceeded rather than waiting for them to drain.
+                        backend.max_concurrency = candidate.max_concurrency;
+                        backend.concurrency = candidate.concurrency;
+                    }
+                    self.backends.push(backend);
+                }
+                None => {
+                    added.push(candidate.id);
+                    self.backends.push(candidate);
+                }
+            }
+        }
+        Ok((added, old))
+    }
+}
+
+/// Which KV cache block samples to read from a metrics page.
+struct KvMetricsFilter {
+    pub(crate) name: Option<String>,
+    pub(crate) model: String,
+    pub(crate) versions: Vec<String>,
+    pub(crate) labels: KvMetricsLabels,
+}
+
+impl KvMetricsFilter {
+    fn from_config(config: &LbConfig) -> Self {
+        KvMetricsFilter {
+            name: config.kv_metrics_name.clone(),
+            model: config.kv_metrics_model.clone(),
+            versions: config.kv_metrics_version.as_slice().to_vec(),
+            labels: config.kv_metrics_labels.clone(),
+        }
+    }
+
+    pub(crate) fn matches(&self, sample: &Sample<'_>) -> bool {
+        let labels = &self.labels;
+        self.name.as_deref().is_none_or(|name| sample.name == name)
+            && (labels.model.is_empty() || sample.label(&labels.model) == Some(self.model.as_str()))
+            && (labels.version.is_empty()
+                || sample
+                    .label(&labels.version)
+                    .is_some_and(|version| self.versions.iter().any(|v| v == version)))
+            && labels
+                .required
+                .iter()
+                .all(|(k, v)| sample.label(k) == Some(v.as_str()))
+    }
+
+    /// The version a matching sample belongs to; all the same without a version label.
+    fn version_of(&self, sample: &Sample<'_>) -> String {
+        if self.labels.version.is_empty() {
+            String::new()
+        } else {
+            sample
+                .label(&self.labels.version)
+                .unwrap_or_default()
+                .to_string()
+        }
+    }
+}
+
+/// Share of the poll interval a scrape may come early or late by.
+const POLL_JITTER: f64 = 0.1;
+/// Sent with every metrics scrape, so backends can tell the load balancer's polling apart.
+const METRICS_USER_AGENT: &str = concat!("rust-lb-metrics/", env!("CARGO_PKG_VERSION"));
+
+/// Why a metrics scrape failed.
+#[derive(Debug)]
+enum ScrapeError {
+    /// No complete page within `metrics_timeout_secs`.
+    Timeout(Duration),
+    /// No connection to the endpoint: refused, unresolvable, or not accepted within
+    /// `metrics_connect_timeout_ms`.
+    Connect(String),
+    /// The request couldn't be sent or the connection broke.
+    Connection(String),
+    /// The endpoint answered with an error status.
+    Status(StatusCode),
+}
+
+impl ScrapeError {
+    /// Label for `lb_metrics_scrape_errors_total`.
+    fn reason(&self) -> &'static str {
+        match self {
+            ScrapeError::Timeout(_) => "timeout",
+            ScrapeError::Connect(_) => "connect",
+            ScrapeError::Connection(_) => "connection",
+            ScrapeError::Status(_) => "bad_response",
+        }
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScrapeError::Timeout(limit) => {
+                write!(f, "Metrics request timed out after {}s", limit.as_secs())
+            }
+            ScrapeError::Connect(e) => write!(f, "Metrics endpoint unreachable: {}", e),
+            ScrapeError::Connection(e) => write!(f, "Metrics request error: {}", e),
+            ScrapeError::Status(status) => write!(f, "Metrics endpoint returned {}", status),
+        }
+    }
+}
+
+/// Fetches a Triton metrics page, giving up after `limit`. `Err` means the scrape itself failed.
+async fn fetch_metrics_page(
+    client: &UpstreamClient,
+    url: &str,
+    limit: Duration,
+) -> Result<String, ScrapeError> {
+    let req = Request::builder()
+        .method("GET")
+        .uri(url)
+        .header(USER_AGENT, METRICS_USER_AGENT)
+        .body(Body::empty())
+        .map_err(|e| ScrapeError::Connection(format!("failed to build request: {}", e)))?;
+    let fetch = async {
+        let resp = client.request(req).await.map_err(|e| {
+            if e.is_connect() {
+                ScrapeError::Connect(e.to_string())
+            } else {
+                ScrapeError::Connection(e.to_string())
+            }
+        })?;
+        if !resp.status().is_success() {
+            return Err(ScrapeError::Status(resp.status()));
+        }
+        hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|e| ScrapeError::Connection(format!("failed to read body: {}", e)))
+    };
+    let body_bytes = timeout(limit, fetch)
+        .await
+        .map_err(|_| ScrapeError::Timeout(limit))??;
+
+    Ok(String::from_utf8_lossy(&body_bytes).into_owned())
+}
+
+/// Scans Prometheus text for the used and max KV cache block gauges matching `filter`. With
+/// several versions, the blocks of every version reporting both gauges are added up.
+fn parse_kv_cache(metrics_text: &str, filter: &KvMetricsFilter) -> Option<(f64, f64)> {
+    let mut by_version: BTreeMap<String, (Option<f64>, Option<f64>)> = BTreeMap::new();
+    for sample in metrics_text.lines().filter_map(parse_sample) {
+        if !filter.matches(&sample) {
+            continue;
+        }
+        let (used, max) = by_version.entry(filter.version_of(&sample)).or_default();
+        let block_type = sample.label(&filter.labels.block_type);
+        if block_type == Some(filter.labels.used.as_str()) {
+            *used = Some(sample.value);
+        } else if block_type == Some(filter.labels.max.as_str()) {
+            *max = Some(sample.value);
+        }
+    }
+    let (used, max) = by_version
+        .values()
+        .filter_map(|&(used, max)| Some((used?, max?)))
+        .reduce(|(used, max), (u, m)| (used + u, max + m))?;
+    if max > 0.0 && used >= 0.0 {
+        Some((used, max))
+    } else {
+        None
+    }
+}
+
+/// Reads the `kv_used` and `kv_max` fields of a JSON stats page.
+fn parse_kv_json(page: &str) -> Option<(f64, f64)> {
+    let stats: serde_json::Value = serde_json::from_str(page).ok()?;
+    let used = stats.get("kv_used")?.as_f64()?;
+    let max = stats.get("kv_max")?.as_f64()?;
+    if max > 0.0 && used >= 0.0 {
+        Some((used, max))
+    } else {
+        None
+    }
+}
+
+/// The pressure score of a metrics page: the weighted mean of the KV ratio and each configured
+/// pressure metric's fill level. Metrics missing from the page are left out of the mean.
+fn pressure_score(
+    metrics_text: &str,
+    kv_ratio: f64,
+    kv_weight: f64,
+    metrics: &[PressureMetricConfig],
+) -> f64 {
+    if metrics.is_empty() {
+        return kv_ratio;
+    }
+    let samples: Vec<Sample<'_>> = metrics_text.lines().filter_map(parse_sample).collect();
+    let total_of = |name: &str, labels: &BTreeMap<String, String>| -> Option<f64> {
+        let mut matching = samples
+            .iter()
+            .filter(|s| {
+                s.name == name && labels.iter().all(|(k, v)| s.label(k) == Some(v.as_str()))
+            })
+            .map(|s| s.value)
+            .peekable();
+        matching.peek()?;
+        Some(matching.sum())
+    };
+    let mut weighted = kv_ratio * kv_weight;
+    let mut weights = kv_weight;
+    for metric in metrics {
+        let value = match total_of(&metric.name, &metric.labels) {
+            Some(value) => value,
+            None => continue,
+        };
+        let max = match (&metric.max_metric, metric.max) {
+            (Some(max_metric), _) => total_of(max_metric, &metric.labels),
+            (None, max) => max,
+        };
+        if let Some(max) = max.filter(|&max| max > 0.0) {
+            weighted += (value / max).clamp(0.0, 1.0) * metric.weight;
+            weights += metric.weight;
+        }
+    }
+    if weights > 0.0 {
+        weighted / weights
+    } else {
+        kv_ratio
+    }
+}
+
+/// One sample line of the Prometheus text exposition format.
+struct Sample<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) labels: Vec<(&'a str, String)>,
+    pub(crate) value: f64,
+}
+
+impl Sample<'_> {
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses one sample line of the Prometheus text format. Comments, blank lines and malformed
+/// or non-finite samples yield `None`.
+fn parse_sample(line: &str) -> Option<Sample<'_>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .filter(|&end| end > 0)?;
+    let name = &line[..name_end];
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+    if let Some(mut body) = rest.strip_prefix('{') {
+        loop {
+            body = body.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if let Some(after) = body.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let eq = body.find('=')?;
+            let key = body[..eq].trim();
+            let quoted = body[eq + 1..].trim_start().strip_prefix('"')?;
+            let mut value = String::new();
+            let mut escaped = false;
+            let mut close = None;
+            for (i, c) in quoted.char_indices() {
+                if escaped {
+                    value.push(if c == 'n' { '\n' } else { c });
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    close = Some(i);
+                    break;
+                } else {
+                    value.push(c);
+                }
+            }
+            labels.push((key, value));
+            body = &quoted[close? + 1..];
+        }
+    }
+    let value: f64 = rest.split_whitespace().next()?.parse().ok()?;
+    if value.is_finite() {
+        Some(Sample {
+            name,
+            labels,
+            value,
+        })
+    } else {
+        None
+    }
+}
+
+/// Polls one backend's metrics endpoint every `metrics_poll_interval_secs` and updates its
+/// entry in the shared state. Returns right away for backends without a metrics URL.
+async fn poll_metrics(
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+    id: u64,
+) {
+    let mut failures: u32 = 0;
+    // A random start within the first interval, so backends added together (all of them, at
+    // startup) aren't scraped in step.
+    let first_interval =
+        Duration::from_secs(app_state.read().await.config.metrics_poll_interval_secs);
+    let offset = first_interval.mul_f64(rand::thread_rng().gen_range(0.0..1.0));
+    sleep(offset).await;
+    loop {
+        let (
+            name,
+            url,
+            format,
+            interval,
+            backoff_max,
+            scrape_timeout,
+            filter,
+            kv_weight,
+            pressure_metrics,
+            smoothing,
+        ) = {
+            let state = app_state.read().await;
+            let backend = match state.metrics.get(id) {
+                Some(backend) => backend,
+                None => return, // Removed by a config reload.
+            };
+            let push_ttl = Duration::from_secs(state.config.metrics_push_ttl_secs);
+            if backend
+                .last_pushed
+                .is_some_and(|pushed| pushed.elapsed() < push_ttl)
+            {
+                // The backend reports its own load for now; polling is only the fallback.
+                let interval = Duration::from_secs(state.config.metrics_poll_interval_secs);
+                drop(state);
+                failures = 0;
+                let delay = jittered(interval, &mut rand::thread_rng());
+                sleep(delay).await;
+                continue;
+            }
+            (
+                backend.name.clone(),
+                backend.kv_metrics_url.clone(),
+                backend.metrics_format,
+                Duration::from_secs(state.config.metrics_poll_interval_secs),
+                Duration::from_secs(state.config.metrics_backoff_max_secs),
+                Duration::from_secs(state.config.metrics_timeout_secs),
+                KvMetricsFilter::from_config(&state.config),
+                state.config.kv_pressure_weight,
+                state.config.pressure_metrics.clone(),
+                state.config.kv_smoothing_factor,
+            )
+        };
+        let url = match url {
+            Some(url) => url,
+            None => return,
+        };
+        let result = fetch_metrics_page(&clients.metrics, &url, scrape_timeout)
+            .await
+            .map(|page| match format {
+                MetricsFormat::PrometheusText => {
+                    parse_kv_cache(&page, &filter).map(|(used, max)| {
+                        let pressure =
+                            pressure_score(&page, used / max, kv_weight, &pressure_metrics);
+                        (used, max, pressure)
+                    })
+                }
+                // The pressure metrics are Prometheus series, so a JSON page only has its KV ratio.
+                MetricsFormat::Json => {
+                    parse_kv_json(&page).map(|(used, max)| (used, max, used / max))
+                }
+            });
+        if result.is_ok() {
+            if failures > 0 {
+                info!(backend = %name, failures, "metrics scrape recovered");
+            }
+            failures = 0;
+        }
+        match result {
+            Ok(Some((used_val, max_val, pressure))) => {
+                let ratio = used_val / max_val;
+                debug!(
+                    backend = %name,
+                    used = used_val,
+                    max = max_val,
+                    kv_ratio = ratio,
+                    pressure,
+                    "polled KV cache"
+                );
+                let mut state = app_state.write().await;
+                if !record_load(
+                    &mut state,
+                    &lb_metrics,
+                    id,
+                    used_val,
+                    max_val,
+                    pressure,
+                    smoothing,
+                ) {
+                    return; // Removed by a config reload.
+                }
+            }
+            Ok(None) => {
+                // The server answered, so it is up; keep routing on the last known ratio
+                // rather than taking it out of the pool over a renamed metric.
+                warn!(
+                    backend = %name,
+                    model = %filter.model,
+                    versions = ?filter.versions,
+                    "KV cache metrics missing from scrape, keeping last known ratio"
+                );
+                let mut state = app_state.write().await;
+                let warmup = Duration::from_secs(state.config.warmup_secs);
+                if let Some(backend) = state.metrics.get_mut(id) {
+                    backend.set_online(true, warmup);
+                }
+            }
+            Err(e) => {
+                failures = failures.saturating_add(1);
+                let delay =
+                    scrape_backoff(interval, backoff_max, failures, &mut rand::thread_rng());
+                lb_metrics
+                    .metrics_scrape_errors_total
+                    .with_label_values(&[&name, e.reason()])
+                    .inc();
+                // Only the first failure is worth a warning; the rest would just repeat it.
+                if failures == 1 {
+                    warn!(
+                        backend = %name,
+                        error = %e,
+                        reason = e.reason(),
+                        "metrics scrape failed, marking backend offline"
+                    );
+                } else {
+                    debug!(
+                        backend = %name,
+                        error = %e,
+                        reason = e.reason(),
+                        failures,
+                        retry_in_secs = delay.as_secs_f64(),
+                        "metrics scrape failed again"
+                    );
+                }
+                let mut state = app_state.write().await;
+                if let Some(backend) = state.metrics.get_mut(id) {
+                    backend.set_online(false, Duration::ZERO);
+                }
+                drop(state);
+                sleep(delay).await;
+                continue;
+            }
+        }
+        let delay = jittered(interval, &mut rand::thread_rng());
+        sleep(delay).await;
+    }
+}
+
+/// `interval` give or take `POLL_JITTER` of it, so scrapes that started in step drift apart.
+fn jittered<R: Rng + ?Sized>(interval: Duration, rng: &mut R) -> Duration {
+    interval.mul_f64(rng.gen_range(1.0 - POLL_JITTER..=1.0 + POLL_JITTER))
+}
+
+/// Exponentially weighted moving average: `sample` weighs `alpha`, the history the rest. The
+/// first sample starts it.
+fn ewma(previous: Option<f64>, sample: f64, alpha: f64) -> f64 {
+    match previous {
+        Some(previous) => alpha * sample + (1.0 - alpha) * previous,
+        None => sample,
+    }
+}
+
+/// Applies a KV cache sample, scraped or pushed, to a backend and marks it online. The
+/// pressure is smoothed with weight `smoothing` (1 to take it as is). Returns false if the
+/// backend is no longer configured.
+pub(crate) fn record_load(
+    state: &mut AppState,
+    lb_metrics: &LbMetrics,
+    id: u64,
+    used: f64,
+    max: f64,
+    pressure: f64,
+    smoothing: f64,
+) -> bool {
+    let warmup = Duration::from_secs(state.config.warmup_secs);
+    let load_released = state.load_released.clone();
+    let AppState {
+        config, metrics, ..
+    } = state;
+    let backend = match metrics.get_mut(id) {
+        Some(backend) => backend,
+        None => return false,
+    };
+    let thresholds = match config.backends.iter().find(|b| b.name == backend.name) {
+        Some(backend_config) => config.thresholds(backend_config),
+        None => return false,
+    };
+    let changed = backend.update_load(used, max, pressure, smoothing, thresholds);
+    lb_metrics
+        .backend_kv_ratio
+        .with_label_values(&[&backend.name])
+        .set(used / max);
+    lb_metrics
+        .backend_pressure
+        .with_label_values(&[&backend.name])
+        .set(backend.pressure);
+    if changed {
+        info!(backend = %backend.name, pressure = backend.pressure, shedding = backend.shedding, "shed mode changed");
+        if !backend.shedding {
+            load_released.notify_waiters();
+        }
+    }
+    backend.set_online(true, warmup);
+    true
+}
+
+/// Delay before the next scrape after `failures` consecutive failures: `interval` doubled per
+/// extra failure, capped at `max`, with the upper half jittered so backends don't retry in step.
+/// Never shorter than `interval`.
+fn scrape_backoff<R: Rng + ?Sized>(
+    interval: Duration,
+    max: Duration,
+    failures: u32,
+    rng: &mut R,
+) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    let delay = interval
+        .saturating_mul(1 << doublings)
+        .min(max.max(interval));
+    let half = delay / 2;
+    (half + half.mul_f64(rng.gen_range(0.0..=1.0))).max(interval)
+}
+
+/// Buckets per latency histogram: four per doubling from 1 ms, up to about 17 minutes.
+const LATENCY_BUCKETS: usize = 80;
+
+/// Time-to-response-headers of one backend, bucketed logarithmically like an HDR histogram so it
+/// stays a fixed size and percentiles are exact to within one bucket (about 19%). Samples age out
+/// by rotating two halves: each window the older half is dropped.
+pub(crate) struct LatencyHistogram {
+    pub(crate) current: [u64; LATENCY_BUCKETS],
+    previous: [u64; LATENCY_BUCKETS],
+    rotated_at: Instant,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn new() -> Self {
+        LatencyHistogram {
+            current: [0; LATENCY_BUCKETS],
+            previous: [0; LATENCY_BUCKETS],
+            rotated_at: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self, now: Instant, window: Duration) {
+        let age = now.saturating_duration_since(self.rotated_at);
+        if age < window {
+            return;
+        }
+        // After two idle windows even the newer half is too old to keep.
+        self.previous = if age < window * 2 {
+            self.current
+        } else {
+            [0; LATENCY_BUCKETS]
+        };
+        self.current = [0; LATENCY_BUCKETS];
+        self.rotated_at = now;
+    }
+
+    pub(crate) fn record(&mut self, latency: Duration, now: Instant, window: Duration) {
+        self.rotate(now, window);
+        let millis = latency.as_secs_f64() * 1000.0;
+        let bucket = if millis <= 1.0 {
+            0
+        } else {
+            (millis.log2() * 4.0).ceil() as usize
+        };
+        self.current[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    /// The p50, p95 and p99 latencies in seconds (each its bucket's upper bound), or `None`
+    /// without samples in the window.
+    pub(crate) fn percentiles(&mut self, now: Instant, window: Duration) -> Option<[f64; 3]> {
+        self.rotate(now, window);
+        let counts: Vec<u64> = self
+            .current
+            .iter()
+            .zip(&self.previous)
+            .map(|(a, b)| a + b)
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let quantile = |q: f64| {
+            let rank = ((q * total as f64).ceil() as u64).max(1);
+            let mut seen = 0;
+            let bucket = counts
+                .iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(LATENCY_BUCKETS - 1);
+            2f64.powf(bucket as f64 / 4.0) / 1000.0
+        };
+        Some([quantile(0.5), quantile(0.95), quantile(0.99)])
+    }
+}
+
+/// `quantile` labels of `lb_backend_latency_seconds`, in the order `percentiles` returns them.
+pub(crate) const LATENCY_QUANTILES: [&str; 3] = ["0.5", "0.95", "0.99"];
+
+/// Copies each backend's current latency percentiles into the Prometheus gauges, dropping the
+/// series of backends without recent responses.
+pub(crate) fn refresh_latency_gauges(state: &AppState, lb_metrics: &LbMetrics) {
+    let now = Instant::now();
+    let window = Duration::from_secs(state.config.latency_window_secs);
+    for backend in &state.metrics.backends {
+        let percentiles = lock(&backend.latency).percentiles(now, window);
+        for (i, quantile) in LATENCY_QUANTILES.iter().enumerate() {
+            let labels = [backend.name.as_str(), quantile];
+            match percentiles {
+                Some(values) => lb_metrics
+                    .backend_latency_seconds
+                    .with_label_values(&labels)
+                    .set(values[i]),
+                None => {
+                    let _ = lb_metrics
+                        .backend_latency_seconds
+                        .remove_label_values(&labels);
+                }
+            }
+        }
+    }
+}
+
+/// Probes one backend's health endpoint forever, independently of the metrics scrape.
+async fn health_check_loop(
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    id: u64,
+) {
+    loop {
+        let (name, uri, protocol, interval, probe_timeout) = {
+            let state = app_state.read().await;
+            let backend = match state.metrics.get(id) {
+                Some(backend) => backend,
+                None => return, // Removed by a config reload.
+            };
+            (
+                backend.name.clone(),
+                backend.health_check_uri.clone(),
+                backend.protocol,
+                Duration::from_secs(state.config.health_check_interval_secs),
+                Duration::from_secs(state.config.health_check_timeout_secs),
+            )
+        };
+        let uri = match uri {
+            Some(uri) => uri,
+            None => return,
+        };
+
+        let ok = match timeout(probe_timeout, clients.get(protocol).get(uri)).await {
+            Ok(Ok(resp)) => resp.status().is_success(),
+            Ok(Err(e)) => {
+                debug!(backend = %name, error = %e, "health check failed");
+                false
+            }
+            Err(_) => {
+                debug!(backend = %name, "health check timed out");
+                false
+            }
+        };
+
+        {
+            let mut state = app_state.write().await;
+            let (unhealthy, healthy) = (
+                state.config.unhealthy_threshold,
+                state.config.healthy_threshold,
+            );
+            let warmup = Duration::from_secs(state.config.warmup_secs);
+            let backend = match state.metrics.get_mut(id) {
+                Some(backend) => backend,
+                None => return,
+            };
+            if backend.record_health_check(ok, unhealthy, healthy) {
+                if backend.healthy {
+                    backend.start_warmup(warmup);
+                    info!(backend = %name, "backend is healthy again, adding it back to the pool");
+                } else {
+                    warn!(
+                        backend = %name,
+                        failures = backend.consecutive_failures,
+                        "backend failed its health checks, removing it from the pool"
+                    );
+                }
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Flags backends whose metrics scrape hasn't succeeded within `staleness_secs`, e.g. because
+/// the endpoint hangs, so routing stops trusting their last ratio.
+pub(crate) async fn staleness_loop(app_state: Arc<RwLock<AppState>>) {
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        let mut state = app_state.write().await;
+        let staleness = Duration::from_secs(state.config.staleness_secs);
+        let now = Instant::now();
+        for backend in &mut state.metrics.backends {
+            let stale = !staleness.is_zero()
+                && backend.reports_load()
+                && now.saturating_duration_since(backend.last_updated) > staleness;
+            if stale && !backend.stale {
+                warn!(
+                    backend = %backend.name,
+                    age_secs = now.saturating_duration_since(backend.last_updated).as_secs(),
+                    "KV cache ratio is stale, treating the backend as full"
+                );
+                backend.shedding = true;
+            }
+            backend.stale = stale;
+        }
+    }
+}
+
+/// Starts the metrics poller and health checker of one backend. Both exit once the backend is
+/// removed from the state.
+pub(crate) fn spawn_backend_tasks(
+    app_state: &Arc<RwLock<AppState>>,
+    clients: &Arc<UpstreamClients>,
+    lb_metrics: &Arc<LbMetrics>,
+    id: u64,
+) {
+    tokio::spawn(poll_metrics(
+        app_state.clone(),
+        clients.clone(),
+        lb_metrics.clone(),
+        id,
+    ));
+    tokio::spawn(health_check_loop(app_state.clone(), clients.clone(), id));
+}
+
+/// Scrapes and health checks every configured backend once, logging which ones answered and
+/// a summary. Returns how many backends were reachable, counting those with nothing to probe.
+pub(crate) async fn self_test(config: &LbConfig, clients: &UpstreamClients) -> usize {
+    let filter = KvMetricsFilter::from_config(config);
+    let scrape_timeout = Duration::from_secs(config.metrics_timeout_secs);
+    let probe_timeout = Duration::from_secs(config.health_check_timeout_secs);
+    let filter = &filter;
+    let probes = config.backends.iter().map(|backend| async move {
+        let mut problems = Vec::new();
+        if let Some(url) = &backend.kv_metrics_url {
+            match fetch_metrics_page(&clients.metrics, url, scrape_timeout).await {
+                Ok(page) => {
+                    let parsed = match backend.metrics_format.unwrap_or(MetricsFormat::PrometheusText) {
+                        MetricsFormat::PrometheusText => parse_kv_cache(&page, filter),
+                        MetricsFormat::Json => parse_kv_json(&page),
+                    };
+                    if parsed.is_none() {
+                        warn!(backend = %backend.name, url = %url, "metrics page has no KV cache usage");
+                    }
+                }
+                Err(e) => problems.push(format!("metrics: {}", e)),
+            }
+        }
+        if let Some(path) = &backend.health_path {
+            match health_check_uri(&backend.base_uri, path) {
+                Ok(uri) => {
+                    let protocol = backend.protocol.unwrap_or(BackendProtocol::Http1);
+                    match timeout(probe_timeout, clients.get(protocol).get(uri)).await {
+                        Ok(Ok(resp)) if resp.status().is_success() => {}
+                        Ok(Ok(resp)) => problems.push(format!("health check: returned {}", resp.status())),
+                        Ok(Err(e)) => problems.push(format!("health check: {}", e)),
+                        Err(_) => problems.push(format!("health check: timed out after {}s", probe_timeout.as_secs())),
+                    }
+                }
+                Err(e) => problems.push(format!("health check: invalid health_path: {}", e)),
+            }
+        }
+        let probed = backend.kv_metrics_url.is_some() || backend.health_path.is_some();
+        match (probed, problems.is_empty()) {
+            (false, _) => info!(backend = %backend.name, "backend has no metrics or health endpoint to probe"),
+            (true, true) => info!(backend = %backend.name, "backend is reachable"),
+            (true, false) => warn!(backend = %backend.name, problems = %problems.join("; "), "backend is unreachable"),
+        }
+        problems.is_empty()
+    });
+    let results = futures_util::future::join_all(probes).await;
+    let reachable = results.iter().filter(|&&ok| ok).count();
+    let unreachable = results.len() - reachable;
+    if unreachable > 0 {
+        warn!(
+            reachable,
+            unreachable, "startup self-test found unreachable backends"
+        );
+    } else {
+        info!(reachable, "startup self-test reached every backend");
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Triton page with version 1 at 30/100 blocks and version 2 at 10/50.
+    const MIXED_VERSIONS: &str = "\
+# TYPE nv_trt_llm_kv_cache_block_metrics gauge
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"used\",model=\"tensorrt_llm\",version=\"1\"} 30
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"max\",model=\"tensorrt_llm\",version=\"1\"} 100
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"used\",model=\"tensorrt_llm\",version=\"2\"} 10
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"max\",model=\"tensorrt_llm\",version=\"2\"} 50
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"used\",model=\"other\",version=\"1\"} 99
+";
+
+    /// Trimmed from the `/metrics` page of a Triton server running the TensorRT-LLM backend.
+    const TRITON_PAGE: &str = "\
+# HELP nv_inference_request_success Number of successful inference requests, all batch sizes
+# TYPE nv_inference_request_success counter
+nv_inference_request_success{model=\"ensemble\",version=\"1\"} 42
+nv_inference_request_success{model=\"tensorrt_llm\",version=\"1\"} 42
+# HELP nv_inference_queue_duration_us Cumulative inference queuing duration in microseconds
+# TYPE nv_inference_queue_duration_us counter
+nv_inference_queue_duration_us{model=\"tensorrt_llm\",version=\"1\"} 18327
+# HELP nv_trt_llm_request_metrics TRT LLM request metrics
+# TYPE nv_trt_llm_request_metrics gauge
+nv_trt_llm_request_metrics{model=\"tensorrt_llm\",request_type=\"context\",version=\"1\"} 1
+nv_trt_llm_request_metrics{model=\"tensorrt_llm\",request_type=\"active\",version=\"1\"} 3
+# HELP nv_trt_llm_kv_cache_block_metrics TRT LLM KV cache block metrics
+# TYPE nv_trt_llm_kv_cache_block_metrics gauge
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"tokens_per\",model=\"tensorrt_llm\",version=\"1\"} 64
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"used\",model=\"tensorrt_llm\",version=\"1\"} 1283
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"free\",model=\"tensorrt_llm\",version=\"1\"} 4956
+nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"max\",model=\"tensorrt_llm\",version=\"1\"} 6239
+# HELP nv_gpu_utilization GPU utilization rate [0.0 - 1.0)
+# TYPE nv_gpu_utilization gauge
+nv_gpu_utilization{gpu_uuid=\"GPU-3b2c7a4e-1f4d-9c3e-5b8e-2a6f0d1c9e7b\"} 0.87
+";
+
+    fn filter(versions: &[&str]) -> KvMetricsFilter {
+        KvMetricsFilter {
+            name: None,
+            model: "tensorrt_llm".to_string(),
+            versions: versions.iter().map(|v| v.to_string()).collect(),
+            labels: KvMetricsLabels::default(),
+        }
+    }
+
+    #[test]
+    fn a_single_version_ignores_the_others() {
+        assert_eq!(
+            parse_kv_cache(MIXED_VERSIONS, &filter(&["1"])),
+            Some((30.0, 100.0))
+        );
+        assert_eq!(
+            parse_kv_cache(MIXED_VERSIONS, &filter(&["2"])),
+            Some((10.0, 50.0))
+        );
+    }
+
+    #[test]
+    fn several_versions_are_summed() {
+        assert_eq!(
+            parse_kv_cache(MIXED_VERSIONS, &filter(&["1", "2"])),
+            Some((40.0, 150.0))
+        );
+    }
+
+    #[test]
+    fn versions_missing_a_gauge_are_left_out() {
+        let page = format!(
+            "{}nv_trt_llm_kv_cache_block_metrics{{kv_cache_block_type=\"used\",model=\"tensorrt_llm\",version=\"3\"}} 5\n",
+            MIXED_VERSIONS
+        );
+        assert_eq!(
+            parse_kv_cache(&page, &filter(&["1", "3"])),
+            Some((30.0, 100.0))
+        );
+        assert_eq!(parse_kv_cache(&page, &filter(&["3"])), None);
+        assert_eq!(parse_kv_cache(&page, &filter(&["4"])), None);
+    }
+
+    #[test]
+    fn a_triton_page_yields_its_kv_blocks() {
+        assert_eq!(
+            parse_kv_cache(TRITON_PAGE, &filter(&["1"])),
+            Some((1283.0, 6239.0))
+        );
+        // Timestamps after the value are allowed by the format.
+        let stamped = TRITON_PAGE.replace("} 1283\n", "} 1283 1712345678901\n");
+        assert_eq!(
+            parse_kv_cache(&stamped, &filter(&["1"])),
+            Some((1283.0, 6239.0))
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let broken = [
+            "nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"used\",model=\"tensorrt_llm\",version=\"1\" 9999",
+            "nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=used,model=\"tensorrt_llm\",version=\"1\"} 9999",
+            "nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"max\",model=\"tensorrt_llm\",version=\"1\"}",
+            "nv_trt_llm_kv_cache_block_metrics{kv_cache_block_type=\"max\",model=\"tensorrt_llm\",version=\"1\"} lots",
+            "{kv_cache_block_type=\"used\"} 9999",
+            "not a metric at all",
+        ];
+        for line in broken {
+            assert!(parse_sample(line).is_none(), "{}", line);
+            let page = format!("{}{}\n", TRITON_PAGE, line);
+            assert_eq!(
+                parse_kv_cache(&page, &filter(&["1"])),
+                Some((1283.0, 6239.0)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn a_page_without_both_gauges_has_no_kv_blocks() {
+        let without = |block_type: &str| {
+            let needle = format!("kv_cache_block_type=\"{}\"", block_type);
+            TRITON_PAGE
+                .lines()
+                .filter(|line| !line.contains(&needle))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        assert_eq!(parse_kv_cache(&without("used"), &filter(&["1"])), None);
+        assert_eq!(parse_kv_cache(&without("max"), &filter(&["1"])), None);
+        let renamed = TRITON_PAGE.replace(
+            "nv_trt_llm_kv_cache_block_metrics",
+            "nv_trt_llm_kv_cache_blocks",
+        );
+        let named = KvMetricsFilter {
+            name: Some("nv_trt_llm_kv_cache_block_metrics".to_string()),
+            ..filter(&["1"])
+        };
+        assert_eq!(parse_kv_cache(TRITON_PAGE, &named), Some((1283.0, 6239.0)));
+        assert_eq!(parse_kv_cache(&renamed, &named), None);
+        assert_eq!(parse_kv_cache("", &filter(&["1"])), None);
+    }
+
+    #[test]
+    fn non_finite_gauges_are_ignored() {
+        for value in ["NaN", "+Inf", "-Inf"] {
+            let used = TRITON_PAGE.replace("} 1283\n", &format!("}} {}\n", value));
+            assert_eq!(
+                parse_kv_cache(&used, &filter(&["1"])),
+                None,
+                "used {}",
+                value
+            );
+            let max = TRITON_PAGE.replace("} 6239\n", &format!("}} {}\n", value));
+            assert_eq!(parse_kv_cache(&max, &filter(&["1"])), None, "max {}", value);
+        }
+        let zero_max = TRITON_PAGE.replace("} 6239\n", "} 0\n");
+        assert_eq!(parse_kv_cache(&zero_max, &filter(&["1"])), None);
+    }
+
+    #[test]
+    fn the_label_template_matches_other_naming() {
+        let page = "\
+vllm_kv_blocks{kind=\"in_use\",gpu=\"0\"} 20
+vllm_kv_blocks{kind=\"total\",gpu=\"0\"} 80
+vllm_kv_blocks{kind=\"in_use\",gpu=\"1\"} 70
+vllm_kv_blocks{kind=\"total\",gpu=\"1\"} 80
+";
+        let config = LbConfig::from_toml(
+            "[kv_metrics_labels]\nblock_type = \"kind\"\nused = \"in_use\"\nmax = \"total\"\nmodel = \"\"\nversion = \"\"\nrequired = { gpu = \"1\" }",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_kv_cache(page, &KvMetricsFilter::from_config(&config)),
+            Some((70.0, 80.0))
+        );
+        assert_eq!(
+            parse_kv_cache(MIXED_VERSIONS, &KvMetricsFilter::from_config(&config)),
+            None
+        );
+
+        assert!(LbConfig::from_toml("[kv_metrics_labels]\nblock_type = \"kind-of\"").is_err());
+        assert!(LbConfig::from_toml("[kv_metrics_labels]\nused = \"max\"").is_err());
+        assert!(LbConfig::from_toml("[kv_metrics_labels]\nmodel = \"version\"").is_err());
+    }
+
+    #[test]
+    fn kv_metrics_version_takes_a_string_or_a_list() {
+        let one = LbConfig::from_toml("kv_metrics_version = \"2\"").unwrap();
+        assert_eq!(one.kv_metrics_version.as_slice(), ["2"]);
+        let several = LbConfig::from_toml("kv_metrics_version = [\"1\", \"2\"]").unwrap();
+        assert_eq!(several.kv_metrics_version.as_slice(), ["1", "2"]);
+        assert!(LbConfig::from_toml("kv_metrics_version = []").is_err());
+    }
+
+    #[test]
+    fn shed_mode_only_flips_when_a_band_is_crossed() {
+        let config = LbConfig::default();
+        let thresholds = config.thresholds(&config.backends[0]);
+        let mut backend = Backend::new(0, &config.backends[0]).unwrap();
+        // (pressure, shedding afterwards, flipped) with the default thresholds of 0.7 and 0.6.
+        let steps = [
+            (0.69, false, false),
+            (0.71, true, true),
+            (0.69, true, false),
+            (0.61, true, false),
+            (0.71, true, false),
+            (0.60, true, false),
+            (0.59, false, true),
+            (0.61, false, false),
+            (0.69, false, false),
+            (0.70, true, true),
+        ];
+        for (pressure, shedding, flipped) in steps {
+            let changed = backend.update_load(pressure * 100.0, 100.0, pressure, 1.0, thresholds);
+            assert_eq!(
+                (backend.shedding, changed),
+                (shedding, flipped),
+                "at {}",
+                pressure
+            );
+        }
+    }
+
+    #[test]
+    fn backends_shed_at_their_own_thresholds() {
+        use crate::config::RoutingStrategy;
+        use crate::selector::{RequestContext, Selector, StrategySelector};
+        use hyper::{HeaderMap, Method};
+
+        let config = LbConfig::from_toml(
+            "[[backends]]\nname = \"l40\"\nbase_uri = \"http://l40:8000\"\ncapacity_threshold = 0.5\nrelease_threshold = 0.4\n\
+             [[backends]]\nname = \"h100\"\nbase_uri = \"http://h100:8000\"\ncapacity_threshold = 0.9\nrelease_threshold = 0.8\n",
+        )
+        .unwrap();
+        let mut backends: Vec<Backend> = config
+            .backends
+            .iter()
+            .zip(0..)
+            .map(|(b, id)| Backend::new(id, b).unwrap())
+            .collect();
+        let headers = HeaderMap::new();
+        let req = RequestContext {
+            method: &Method::POST,
+            path: "/",
+            headers: &headers,
+            pool: "default",
+            attempt: 0,
+        };
+        let mut route = |pressures: [f64; 2], strategy: RoutingStrategy| {
+            for ((backend, backend_config), pressure) in
+                backends.iter_mut().zip(&config.backends).zip(pressures)
+            {
+                backend.online = true;
+                backend.update_load(
+                    pressure * 100.0,
+                    100.0,
+                    pressure,
+                    1.0,
+                    config.thresholds(backend_config),
+                );
+            }
+            let views: Vec<_> = backends.iter().map(|b| b.view(true)).collect();
+            let shedding: Vec<bool> = views.iter().map(|v| v.shedding).collect();
+            (
+                shedding,
+                StrategySelector::new(strategy, Some(7), 0.0).select(&views, &req),
+            )
+        };
+
+        // The same pressure is past the L40's threshold but well within the H100's.
+        assert_eq!(
+            route([0.7, 0.7], RoutingStrategy::FailoverOrder),
+            (vec![true, false], Some(1))
+        );
+        // Each releases at its own lower threshold.
+        assert_eq!(
+            route([0.45, 0.85], RoutingStrategy::FailoverOrder),
+            (vec![true, false], Some(1))
+        );
+        assert_eq!(
+            route([0.35, 0.85], RoutingStrategy::FailoverOrder),
+            (vec![false, false], Some(0))
+        );
+        assert_eq!(
+            route([0.55, 0.95], RoutingStrategy::FailoverOrder),
+            (vec![true, true], Some(0))
+        );
+    }
+
+    #[test]
+    fn unparseable_backends_are_refused_without_touching_the_list() {
+        let good: BackendConfig =
+            serde_json::from_str(r#"{"name":"good","base_uri":"http://good:8000"}"#).unwrap();
+        let bad: BackendConfig =
+            serde_json::from_str(r#"{"name":"bad","base_uri":"not a uri"}"#).unwrap();
+        assert!(Backend::new(0, &bad).is_err());
+        assert!(MetricsState::new(&[good.clone(), bad.clone()]).is_err());
+
+        let mut state = MetricsState::new(&[good]).unwrap();
+        assert!(state.reconcile(&[bad]).is_err());
+        let names: Vec<&str> = state.backends.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["good"]);
+    }
+
+    #[test]
+    fn poll_intervals_are_jittered_around_the_configured_one() {
+        let mut rng = rand::thread_rng();
+        let interval = Duration::from_secs(10);
+        let delays: Vec<Duration> = (0..100).map(|_| jittered(interval, &mut rng)).collect();
+        assert!(
+            delays
+                .iter()
+                .all(|&d| d >= Duration::from_secs(9) && d <= Duration::from_secs(11)),
+            "{:?}",
+            delays
+        );
+        assert!(delays.iter().any(|&d| d != delays[0]), "delays should vary");
+    }
+
+    #[test]
+    fn ewma_starts_at_the_first_sample_and_converges() {
+        let mut smoothed = None;
+        for _ in 0..3 {
+            smoothed = Some(ewma(smoothed, 0.2, 0.5));
+        }
+        assert_eq!(smoothed, Some(0.2));
+        // After a step to 1.0 the gap halves with every sample.
+        let expected = [0.6, 0.8, 0.9, 0.95];
+        for want in expected {
+            smoothed = Some(ewma(smoothed, 1.0, 0.5));
+            assert!(
+                (smoothed.unwrap() - want).abs() < 1e-9,
+                "{:?} != {}",
+                smoothed,
+                want
+            );
+        }
+        assert_eq!(ewma(Some(0.3), 0.9, 1.0), 0.9);
+    }
+
+    #[test]
+    fn smoothing_rides_out_a_single_spike() {
+        let config = LbConfig::from_toml("kv_smoothing_factor = 0.3").unwrap();
+        let thresholds = config.thresholds(&config.backends[0]);
+        let mut backend = Backend::new(0, &config.backends[0]).unwrap();
+        for _ in 0..5 {
+            backend.update_load(40.0, 100.0, 0.4, 0.3, thresholds);
+        }
+        backend.update_load(95.0, 100.0, 0.95, 0.3, thresholds);
+        assert_eq!(backend.kv_ratio, 0.95);
+        assert_eq!(backend.raw_pressure, Some(0.95));
+        assert!(
+            (backend.pressure - 0.565).abs() < 1e-9,
+            "{}",
+            backend.pressure
+        );
+        assert!(!backend.shedding);
+        assert!(LbConfig::from_toml("kv_smoothing_factor = 0").is_err());
+    }
+
+    #[test]
+    fn priority_offsets_move_the_shedding_point() {
+        let config =
+            LbConfig::from_toml("priority_threshold_offsets = { high = 0.15, low = -0.2 }")
+                .unwrap();
+        let (high, low) = (
+            config.priority_offset("HIGH"),
+            config.priority_offset("low"),
+        );
+        assert_eq!(config.priority_offset("normal"), 0.0);
+        let mut backend = Backend::new(0, &config.backends[0]).unwrap();
+        backend.update_load(
+            75.0,
+            100.0,
+            0.75,
+            1.0,
+            config.thresholds(&config.backends[0]),
+        );
+        assert!(backend.sheds_for(0.7, 0.0));
+        assert!(!backend.sheds_for(0.7, high));
+        backend.update_load(
+            55.0,
+            100.0,
+            0.55,
+            1.0,
+            config.thresholds(&config.backends[0]),
+        );
+        assert!(!backend.sheds_for(0.7, 0.0));
+        assert!(backend.sheds_for(0.7, low));
+        assert!(LbConfig::from_toml("priority_threshold_offsets = { high = 1.5 }").is_err());
+    }
+}
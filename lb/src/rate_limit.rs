@@ -0,0 +1,133 @@
+//! Token-bucket rate limiting of incoming requests, globally and per client.
+
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::time::Instant;
+use tokio::time::Duration;
+
+/// Most client IPs with their own token bucket; the least recently seen is forgotten beyond this.
+const MAX_RATE_LIMITED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimit {
+    per_second: f64,
+    pub(crate) burst: f64,
+}
+
+impl RateLimit {
+    pub(crate) fn new(per_second: Option<f64>, burst: Option<u32>) -> Option<Self> {
+        let per_second = per_second?;
+        Some(RateLimit {
+            per_second,
+            burst: burst.map_or(per_second.ceil().max(1.0), f64::from),
+        })
+    }
+}
+
+/// Tokens refill continuously at `RateLimit::per_second` up to `RateLimit::burst`; each
+/// request takes one.
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn full(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            refilled_at: now,
+        }
+    }
+
+    /// Refills, then returns how long until a token is available (zero if one is).
+    pub(crate) fn wait(&mut self, limit: RateLimit, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second)
+        }
+    }
+}
+
+pub(crate) struct RateLimiter {
+    global: Option<TokenBucket>,
+    pub(crate) clients: LruCache<IpAddr, TokenBucket>,
+}
+
+/// Which limit turned a request away.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RateLimitScope {
+    Global,
+    Client,
+}
+
+impl RateLimitScope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitScope::Global => "global",
+            RateLimitScope::Client => "client",
+        }
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new() -> Self {
+        RateLimiter {
+            global: None,
+            clients: LruCache::new(
+                NonZeroUsize::new(MAX_RATE_LIMITED_CLIENTS).unwrap_or(NonZeroUsize::MIN),
+            ),
+        }
+    }
+
+    /// Takes a token from the global bucket and the client's bucket, or from neither if either
+    /// is empty, in which case it returns the limit that was hit and how long to wait.
+    pub(crate) fn check(
+        &mut self,
+        client: IpAddr,
+        global: Option<RateLimit>,
+        per_client: Option<RateLimit>,
+        now: Instant,
+    ) -> Result<(), (RateLimitScope, Duration)> {
+        let global_bucket = match global {
+            Some(limit) => {
+                let bucket = self
+                    .global
+                    .get_or_insert_with(|| TokenBucket::full(limit, now));
+                let wait = bucket.wait(limit, now);
+                if !wait.is_zero() {
+                    return Err((RateLimitScope::Global, wait));
+                }
+                Some(bucket)
+            }
+            None => {
+                self.global = None;
+                None
+            }
+        };
+        let client_bucket = match per_client {
+            Some(limit) => {
+                let bucket = self
+                    .clients
+                    .get_or_insert_mut(client, || TokenBucket::full(limit, now));
+                let wait = bucket.wait(limit, now);
+                if !wait.is_zero() {
+                    return Err((RateLimitScope::Client, wait));
+                }
+                Some(bucket)
+            }
+            None => {
+                self.clients.clear();
+                None
+            }
+        };
+        for bucket in global_bucket.into_iter().chain(client_bucket) {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
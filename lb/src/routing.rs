@@ -0,0 +1,2210 @@
+//! Picking a backend for each request and forwarding it there, with failover.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING,
+    CONTENT_LENGTH, CONTENT_TYPE, HOST, RETRY_AFTER, TE, TRANSFER_ENCODING, UPGRADE, VARY,
+};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use lru::LruCache;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tokio::io::copy_bidirectional;
+use tokio::sync::{Notify, OwnedSemaphorePermit, RwLock};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error, info, warn, Level};
+
+use crate::breaker::{record_outcome, CircuitBreaker};
+use crate::config::{HostHeader, LbConfig, RoutingStrategy};
+use crate::lb_metrics::{GaugeGuard, LbMetrics};
+use crate::metrics::{Backend, LatencyHistogram};
+use crate::selector::{BackendView, RequestContext, Selector};
+use crate::upstream::UpstreamClients;
+use crate::{error_response, lock, AppState, ConnInfo, ErrorResponse, RoutedTo, X_REQUEST_ID};
+
+/// Longest a backend's `Retry-After` keeps it out of rotation, so a bogus date can't take it
+/// out for good.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_LB_DECISION: HeaderName = HeaderName::from_static("x-lb-decision");
+const X_LB_REASON: HeaderName = HeaderName::from_static("x-lb-reason");
+const X_FORCE_BACKEND: HeaderName = HeaderName::from_static("x-force-backend");
+const X_PRIORITY: HeaderName = HeaderName::from_static("x-priority");
+const X_ACCEL_BUFFERING: HeaderName = HeaderName::from_static("x-accel-buffering");
+
+/// With `normalize_error_bodies`, only this much of a backend's error body is read.
+const MAX_ERROR_BODY_BYTES: usize = 64 * 1024;
+
+/// Bounded map from session ID to the name of the backend serving that session.
+pub(crate) struct SessionMap {
+    pub(crate) entries: LruCache<String, SessionEntry>,
+    pub(crate) ttl: Duration,
+}
+
+pub(crate) struct SessionEntry {
+    pub(crate) backend: String,
+    last_used: Instant,
+}
+
+impl SessionMap {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        SessionMap {
+            entries: LruCache::new(capacity),
+            ttl,
+        }
+    }
+
+    /// The backend assigned to `session`, unless the assignment has expired.
+    pub(crate) fn get(&mut self, session: &str, now: Instant) -> Option<&str> {
+        let expired = now.duration_since(self.entries.peek(session)?.last_used) > self.ttl;
+        if expired {
+            self.entries.pop(session);
+            return None;
+        }
+        let entry = self.entries.get_mut(session)?;
+        entry.last_used = now;
+        Some(&entry.backend)
+    }
+
+    fn assign(&mut self, session: &str, backend: &str, now: Instant) {
+        self.entries.put(
+            session.to_string(),
+            SessionEntry {
+                backend: backend.to_string(),
+                last_used: now,
+            },
+        );
+    }
+}
+
+/// Asks `selector` for a backend among `views`, the views of the backends in `pool`, and
+/// returns its index in `backends`.
+///
+/// A backend still warming up keeps only its ramp's share of the requests it is picked for;
+/// the rest go to the selector's next choice, if there is one.
+fn select_backend<R: Rng + ?Sized>(
+    selector: &dyn Selector,
+    backends: &[Backend],
+    pool: &[usize],
+    mut views: Vec<BackendView<'_>>,
+    req: &RequestContext<'_>,
+    rng: &mut R,
+) -> Option<usize> {
+    let choice = checked_select(selector, &views, req)?;
+    let ramp = backends[pool[choice]].ramp(Instant::now());
+    if ramp < 1.0 && !rng.gen_bool(ramp) {
+        views[choice].available = false;
+        if let Some(other) = checked_select(selector, &views, req) {
+            return Some(pool[other]);
+        }
+    }
+    Some(pool[choice])
+}
+
+/// The selector's choice, unless it isn't an available backend.
+fn checked_select(
+    selector: &dyn Selector,
+    views: &[BackendView<'_>],
+    req: &RequestContext<'_>,
+) -> Option<usize> {
+    let choice = selector.select(views, req)?;
+    if views.get(choice).is_some_and(|view| view.available) {
+        Some(choice)
+    } else {
+        warn!(
+            selector = selector.name(),
+            choice, "selector chose an unavailable backend, ignoring it"
+        );
+        None
+    }
+}
+
+/// Counts a request against a backend's in-flight total, and holds its concurrency permit,
+/// until dropped.
+struct InFlightGuard {
+    counter: Arc<AtomicUsize>,
+    /// `failover_in_flight`, for requests that failed over to this backend.
+    failover: Option<Arc<AtomicUsize>>,
+    permit: Option<(OwnedSemaphorePermit, Arc<Notify>)>,
+}
+
+impl InFlightGuard {
+    /// None if the backend reached its `max_concurrency` since it was selected, or, for a
+    /// failover (with a `max_failover_in_flight`), that limit.
+    pub(crate) fn new(
+        backend: &Backend,
+        released: &Arc<Notify>,
+        failover_limit: Option<usize>,
+    ) -> Option<Self> {
+        let failover = match failover_limit {
+            Some(limit) => {
+                let counter = &backend.failover_in_flight;
+                let limit = if limit == 0 { usize::MAX } else { limit };
+                counter
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                        (n < limit).then_some(n + 1)
+                    })
+                    .ok()?;
+                Some(counter.clone())
+            }
+            None => None,
+        };
+        let permit = match &backend.concurrency {
+            Some(permits) => match permits.clone().try_acquire_owned() {
+                Ok(permit) => Some((permit, released.clone())),
+                Err(_) => {
+                    if let Some(counter) = &failover {
+                        counter.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    return None;
+                }
+            },
+            None => None,
+        };
+        backend.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(InFlightGuard {
+            counter: backend.in_flight.clone(),
+            failover,
+            permit,
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+        if let Some(failover) = &self.failover {
+            failover.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some((permit, released)) = self.permit.take() {
+            drop(permit);
+            released.notify_waiters();
+        }
+    }
+}
+
+/// Armed while waiting for a backend's response headers. Hyper drops the request future when
+/// the client disconnects, which also abandons the backend request; this notices that and
+/// frees a half-open breaker's probe slot, which would otherwise never be resolved. The
+/// in-flight counters take care of themselves through their own guards.
+struct PendingForward {
+    pub(crate) backend: String,
+    pub(crate) breaker: Arc<Mutex<CircuitBreaker>>,
+    armed: bool,
+}
+
+impl PendingForward {
+    pub(crate) fn new(backend: &str, breaker: &Arc<Mutex<CircuitBreaker>>) -> Self {
+        PendingForward {
+            backend: backend.to_string(),
+            breaker: breaker.clone(),
+            armed: true,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for PendingForward {
+    fn drop(&mut self) {
+        if self.armed {
+            info!(backend = %self.backend, "client disconnected, cancelled the backend request");
+            lock(&self.breaker).abandon_probe();
+        }
+    }
+}
+
+/// Streams the backend's response body (and trailers) to the client from a separate task that
+/// owns `guard`, so the request stays counted as in flight until the body is finished. If the
+/// client goes away, the next send fails, the task ends and the guard is dropped.
+///
+/// Each chunk is forwarded as soon as the backend produces it, which is what token streaming
+/// over SSE relies on: never collect the body here. A body still streaming at `deadline` is
+/// cut off. `timer` is stopped once the backend is done with the body, not when the client
+/// goes away.
+fn stream_with_guard(
+    resp: Response<Body>,
+    guard: InFlightGuard,
+    backend: &str,
+    deadline: Option<Instant>,
+    timer: LatencyTimer,
+) -> Response<Body> {
+    let (mut parts, mut upstream) = resp.into_parts();
+    if is_event_stream(&parts.headers) {
+        // Keep proxies in front of us (nginx in particular) from buffering the event stream.
+        parts
+            .headers
+            .insert(X_ACCEL_BUFFERING, HeaderValue::from_static("no"));
+    }
+    let (mut sender, body) = Body::channel();
+    let backend = backend.to_string();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let timed_out =
+            || warn!(backend = %backend, "response_timeout_secs passed, cutting the response off");
+        loop {
+            let chunk = match within(deadline, upstream.data()).await {
+                Some(Some(chunk)) => chunk,
+                Some(None) => break,
+                None => {
+                    timed_out();
+                    timer.finish();
+                    return sender.abort();
+                }
+            };
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    debug!(error = %e, "backend response body failed");
+                    timer.finish();
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+        match within(deadline, upstream.trailers()).await {
+            Some(Ok(Some(trailers))) => {
+                timer.finish();
+                let _ = sender.send_trailers(trailers).await;
+            }
+            Some(_) => timer.finish(),
+            None => {
+                timed_out();
+                timer.finish();
+                sender.abort();
+            }
+        }
+    });
+    Response::from_parts(parts, body)
+}
+
+/// A response with a `retry_on_status` status, kept while the request is retried elsewhere so
+/// the client still gets the backend's own answer if no other backend can take it.
+struct HeldResponse {
+    resp: Response<Body>,
+    in_flight: InFlightGuard,
+    backend: String,
+    deadline: Option<Instant>,
+}
+
+impl HeldResponse {
+    fn relay(self, attempts: usize, gzip_min_bytes: Option<u64>) -> Response<Body> {
+        warn!(backend = %self.backend, status = self.resp.status().as_u16(), attempts, "no backend left to retry on, returning the last response");
+        // Already measured when it arrived.
+        let timer = LatencyTimer::unmeasured();
+        let resp = stream_with_guard(
+            self.resp,
+            self.in_flight,
+            &self.backend,
+            self.deadline,
+            timer,
+        );
+        let mut resp = match gzip_min_bytes {
+            Some(min_bytes) => gzip_response(resp, min_bytes),
+            None => resp,
+        };
+        resp.extensions_mut().insert(RoutedTo {
+            backend: self.backend,
+            attempts,
+        });
+        resp
+    }
+}
+
+/// Measures a forward from dispatch until the backend has finished its response.
+/// Forwards of injected faults carry no histogram and are not measured.
+struct LatencyTimer {
+    histogram: Option<Arc<Mutex<LatencyHistogram>>>,
+    dispatched: Instant,
+    window: Duration,
+}
+
+impl LatencyTimer {
+    fn unmeasured() -> Self {
+        LatencyTimer {
+            histogram: None,
+            dispatched: Instant::now(),
+            window: Duration::ZERO,
+        }
+    }
+
+    fn finish(self) {
+        if let Some(histogram) = self.histogram {
+            let now = Instant::now();
+            lock(&histogram).record(now - self.dispatched, now, self.window);
+        }
+    }
+}
+
+/// `future`'s output, or None if `deadline` passes first.
+async fn within<F: std::future::Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// `secs` as a duration, None for 0.
+fn secs_unless_zero(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Whether the client's `Accept-Encoding` allows gzip, i.e. lists `gzip` or `*` without `q=0`.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            let coding = params.next().unwrap_or("");
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f64>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !refused
+        })
+}
+
+/// Gzips a backend's response on its way to the client, unless the backend already encoded it
+/// or it is streamed: event streams, gRPC and bodies without a `Content-Length` (chunked) are
+/// passed through as they come, as are bodies smaller than `min_bytes`.
+fn gzip_response(resp: Response<Body>, min_bytes: u64) -> Response<Body> {
+    let headers = resp.headers();
+    let len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let has_body = !matches!(
+        resp.status(),
+        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+    );
+    if !has_body
+        || headers.contains_key(CONTENT_ENCODING)
+        || is_event_stream(headers)
+        || is_grpc(headers)
+        || len.is_none_or(|len| len < min_bytes)
+    {
+        return resp;
+    }
+    let (mut parts, mut upstream) = resp.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        while let Some(chunk) = upstream.data().await {
+            let written = chunk
+                .map_err(|e| e.to_string())
+                .and_then(|chunk| encoder.write_all(&chunk).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                debug!(error = %e, "failed to compress the response body");
+                return sender.abort();
+            }
+            // Whatever the encoder has ready goes out with each chunk rather than at the end.
+            if !encoder.get_ref().is_empty() {
+                let compressed = Bytes::from(std::mem::take(encoder.get_mut()));
+                if sender.send_data(compressed).await.is_err() {
+                    return;
+                }
+            }
+        }
+        match encoder.finish() {
+            Ok(rest) => {
+                let _ = sender.send_data(Bytes::from(rest)).await;
+            }
+            Err(e) => {
+                debug!(error = %e, "failed to compress the response body");
+                sender.abort();
+            }
+        }
+    });
+    Response::from_parts(parts, body)
+}
+
+/// Replaces the body of a backend's error response with `{"error", "upstream_status",
+/// "backend"}`, keeping its status and other headers.
+async fn normalize_error_body(resp: Response<Body>, backend: &str) -> Response<Body> {
+    let (mut parts, mut upstream) = resp.into_parts();
+    let mut body = Vec::new();
+    while let Some(chunk) = upstream.data().await {
+        match chunk {
+            Ok(chunk) => {
+                let room = MAX_ERROR_BODY_BYTES - body.len();
+                body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+                if body.len() == MAX_ERROR_BODY_BYTES {
+                    break;
+                }
+            }
+            Err(e) => {
+                debug!(backend, error = %e, "backend error body failed");
+                break;
+            }
+        }
+    }
+    // A compressed body can't be quoted, so only its status is reported.
+    let message = if parts.headers.contains_key(CONTENT_ENCODING) {
+        None
+    } else {
+        upstream_error_message(&body)
+    };
+    let error = message.unwrap_or_else(|| {
+        parts
+            .status
+            .canonical_reason()
+            .unwrap_or("upstream error")
+            .to_string()
+    });
+    for name in [CONTENT_LENGTH, CONTENT_ENCODING, TRANSFER_ENCODING] {
+        parts.headers.remove(name);
+    }
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let envelope =
+        json!({ "error": error, "upstream_status": parts.status.as_u16(), "backend": backend });
+    Response::from_parts(parts, Body::from(envelope.to_string()))
+}
+
+/// The message of a backend's error body: the usual message fields of a JSON body, or the
+/// whole text otherwise. None when the body is empty.
+fn upstream_error_message(body: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(text) {
+        let field = ["/error", "/error/message", "/message", "/detail"]
+            .iter()
+            .find_map(|pointer| value.pointer(pointer).and_then(|v| v.as_str()));
+        if let Some(message) = field {
+            return Some(message.to_string());
+        }
+    }
+    Some(text.to_string())
+}
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/event-stream"))
+}
+
+fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// The backend's scheme and authority joined with the path and query the client asked for.
+fn forward_uri(base: &Uri, requested: &Uri) -> String {
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority().map_or("", |a| a.as_str());
+    let path_and_query = requested.path_and_query().map_or("/", |pq| pq.as_str());
+    format!("{}://{}{}", scheme, authority, path_and_query)
+}
+
+/// Adds the client's address to `X-Forwarded-For` and sets `X-Forwarded-Proto`.
+fn add_forwarding_headers(headers: &mut HeaderMap, conn_info: ConnInfo) {
+    let client_ip = conn_info.remote_addr.ip().to_string();
+    let forwarded_for = match headers.get(&X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, client_ip),
+        None => client_ip,
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(conn_info.proto));
+}
+
+/// Headers that describe one connection rather than the request (RFC 7230, section 6.1), so a
+/// proxy must not pass them on.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes the hop-by-hop headers, the ones the `Connection` header lists and `extra`.
+fn strip_hop_by_hop(headers: &mut HeaderMap, extra: &[String]) {
+    let listed: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    // gRPC needs `TE: trailers` to reach the backend; it is the one value HTTP/2 allows.
+    let keep_te = headers
+        .get(TE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"trailers"));
+    for name in HOP_BY_HOP_HEADERS
+        .iter()
+        .copied()
+        .chain(listed.iter().map(String::as_str))
+    {
+        if !(keep_te && name.eq_ignore_ascii_case("te")) {
+            headers.remove(name);
+        }
+    }
+    for name in extra {
+        headers.remove(name.as_str());
+    }
+}
+
+/// How a proxied request ended, for `lb_request_outcome_total`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    /// A backend's response was returned from the first backend tried.
+    Routed,
+    /// A backend's response was returned after failing over from another one.
+    Failover,
+    /// The backend or the request's deadline ran out of time.
+    Timeout,
+    RejectedRateLimit,
+    /// Shed at random because the pool was over `shed_high_water_mark`.
+    RejectedLoadShed,
+    /// No backend could take the request: all offline, over threshold or at capacity.
+    NoBackend,
+    /// The request was refused before routing, e.g. for its size.
+    InvalidRequest,
+    /// Every attempt failed to get a response.
+    BadGateway,
+    Error,
+}
+
+impl Outcome {
+    fn of(resp: &Response<Body>) -> Self {
+        if let Some(error) = resp.extensions().get::<ErrorResponse>() {
+            return match error.error {
+                "rate_limited" => Outcome::RejectedRateLimit,
+                "load_shed" => Outcome::RejectedLoadShed,
+                "no_backend_available"
+                | "all_backends_over_threshold"
+                | "all_backends_at_capacity"
+                | "forced_backend_unavailable" => Outcome::NoBackend,
+                "backend_timeout" | "request_deadline_exceeded" => Outcome::Timeout,
+                "bad_request" | "payload_too_large" => Outcome::InvalidRequest,
+                "bad_gateway" => Outcome::BadGateway,
+                _ => Outcome::Error,
+            };
+        }
+        match resp.extensions().get::<RoutedTo>() {
+            Some(routed) if routed.attempts > 1 => Outcome::Failover,
+            Some(_) => Outcome::Routed,
+            None => Outcome::Error,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Routed => "routed",
+            Outcome::Failover => "failover",
+            Outcome::Timeout => "timeout",
+            Outcome::RejectedRateLimit => "rejected_rate_limit",
+            Outcome::RejectedLoadShed => "rejected_load_shed",
+            Outcome::NoBackend => "no_backend",
+            Outcome::InvalidRequest => "invalid_request",
+            Outcome::BadGateway => "bad_gateway",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+/// Forwards the request to the appropriate backend based on the current metrics state, counts
+/// how that ended in `lb_request_outcome_total` and names the backend in `served_by_header`.
+pub(crate) async fn route_request(
+    req: Request<Body>,
+    conn_info: ConnInfo,
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+) -> Result<Response<Body>, hyper::Error> {
+    let (served_by, body_log) = {
+        let state = app_state.read().await;
+        (
+            state.config.served_by_header.clone(),
+            body_log_limit(&state.config),
+        )
+    };
+    let request_id = request_id_of(req.headers());
+    let mut resp = forward_request(req, conn_info, app_state, clients, lb_metrics.clone()).await?;
+    let backend = resp
+        .extensions()
+        .get::<RoutedTo>()
+        .map_or("", |routed| routed.backend.as_str());
+    lb_metrics
+        .request_outcome_total
+        .with_label_values(&[Outcome::of(&resp).as_str(), backend])
+        .inc();
+    // Also on the timeouts and 502s answered for a backend; responses that never got to one
+    // have no backend to name.
+    let served_by = served_by.and_then(|name| HeaderName::from_bytes(name.as_bytes()).ok());
+    if let (Some(name), Ok(value), false) = (
+        served_by,
+        HeaderValue::from_str(backend),
+        backend.is_empty(),
+    ) {
+        resp.headers_mut().insert(name, value);
+    }
+    if let Some(max) = body_log.filter(|_| resp.status() != StatusCode::SWITCHING_PROTOCOLS) {
+        resp = resp.map(|body| log_body(body, "response", request_id, max));
+    }
+    Ok(resp)
+}
+
+/// How much of each body to log, if `body_log` is on and its debug lines would be kept.
+fn body_log_limit(config: &LbConfig) -> Option<usize> {
+    let enabled = config.body_log && tracing::enabled!(target: "body_log", Level::DEBUG);
+    enabled.then_some(config.body_log_max_bytes)
+}
+
+fn request_id_of(headers: &HeaderMap) -> String {
+    headers
+        .get(&X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Logs the first `max` bytes of `bytes`, a whole body of `len` bytes.
+fn log_body_prefix(direction: &'static str, request_id: &str, bytes: &[u8], len: u64, max: usize) {
+    let logged = &bytes[..bytes.len().min(max)];
+    debug!(
+        target: "body_log",
+        request_id,
+        direction,
+        len,
+        truncated = len > logged.len() as u64,
+        body = %String::from_utf8_lossy(logged),
+        "body"
+    );
+}
+
+/// Passes `body` through unchanged, logging its first `max` bytes once it has ended.
+fn log_body(mut body: Body, direction: &'static str, request_id: String, max: usize) -> Body {
+    let (mut sender, logged) = Body::channel();
+    tokio::spawn(async move {
+        let mut prefix: Vec<u8> = Vec::new();
+        let mut len: u64 = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            len += chunk.len() as u64;
+            let room = max.saturating_sub(prefix.len());
+            prefix.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        log_body_prefix(direction, &request_id, &prefix, len, max);
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    logged
+}
+
+/// Picks a backend for the request and forwards it there.
+///
+/// If the chosen backend refuses the connection, or answers with one of the `retry_on_status`
+/// statuses, the request is retried on the next best backend up to `max_retries` times.
+/// Retrying means replaying the body, so it is buffered when retries are enabled and streamed
+/// straight through otherwise.
+async fn forward_request(
+    mut req: Request<Body>,
+    conn_info: ConnInfo,
+    app_state: Arc<RwLock<AppState>>,
+    clients: Arc<UpstreamClients>,
+    lb_metrics: Arc<LbMetrics>,
+) -> Result<Response<Body>, hyper::Error> {
+    let _timer = lb_metrics.request_duration_seconds.start_timer();
+    let _in_flight = GaugeGuard::new(&lb_metrics.requests_in_flight);
+    let started = Instant::now();
+    // The Upgrade and Connection headers are hop-by-hop and stripped below, so a WebSocket
+    // handshake has to be recognized first.
+    let websocket = is_websocket_upgrade(req.headers());
+    let mut client_upgrade = websocket.then(|| hyper::upgrade::on(&mut req));
+    let (
+        max_retries,
+        retry_on_status,
+        max_body_bytes,
+        backend_timeout,
+        deadline,
+        breaker_settings,
+        latency_window,
+        retry_after,
+        normalize_errors,
+        session_id,
+        forced,
+        priority_offset,
+        model_peek,
+        gzip_min_bytes,
+        host_header,
+        body_log,
+        stream_path,
+        first_byte_timeout,
+        response_timeout,
+    ) = {
+        let state = app_state.read().await;
+        strip_hop_by_hop(req.headers_mut(), &state.config.strip_headers);
+        // Counted before the rate limits, so the clients they turn away still show up.
+        lock(&state.client_counts).record(conn_info.remote_addr.ip(), Instant::now());
+        let (global_limit, client_limit) = state.config.rate_limits();
+        if global_limit.is_some() || client_limit.is_some() {
+            let client_ip = conn_info.remote_addr.ip();
+            let checked = lock(&state.rate_limiter).check(
+                client_ip,
+                global_limit,
+                client_limit,
+                Instant::now(),
+            );
+            if let Err((scope, wait)) = checked {
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                let scope = scope.as_str();
+                // Debug only: a flood of rejections would otherwise flood the log as well.
+                debug!(client = %client_ip, scope, status = 429, "rate limit exceeded");
+                let mut resp = error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    format!("too many requests ({} limit); retry later", scope),
+                    json!({ "scope": scope, "retry_after_secs": retry_after }),
+                );
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return Ok(resp);
+            }
+        }
+        let session_id = if state.config.session_affinity {
+            req.headers()
+                .get(state.config.session_header.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        } else {
+            None
+        };
+        let forced = if state.config.allow_force_backend {
+            req.headers()
+                .get(&X_FORCE_BACKEND)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        } else {
+            None
+        };
+        let priority_offset = req
+            .headers()
+            .get(&X_PRIORITY)
+            .and_then(|v| v.to_str().ok())
+            .map_or(0.0, |priority| {
+                state.config.priority_offset(priority.trim())
+            });
+        (
+            state.config.max_retries,
+            state.config.retry_on_status.clone(),
+            state.config.max_body_bytes,
+            Duration::from_secs(state.config.backend_timeout_secs),
+            match state.config.request_deadline_secs {
+                0 => None,
+                secs => Some(started + Duration::from_secs(secs)),
+            },
+            state.config.breaker_settings(),
+            Duration::from_secs(state.config.latency_window_secs),
+            state.config.unavailable_retry_after_secs,
+            state.config.normalize_error_bodies,
+            session_id,
+            forced,
+            priority_offset,
+            state
+                .config
+                .model_pointer
+                .clone()
+                .map(|pointer| (pointer, state.config.model_peek_max_bytes)),
+            state
+                .config
+                .gzip_responses
+                .then_some(state.config.gzip_min_bytes),
+            state.config.host_header,
+            body_log_limit(&state.config),
+            state.config.streams_body(req.uri().path()),
+            secs_unless_zero(state.config.first_byte_timeout_secs),
+            secs_unless_zero(state.config.response_timeout_secs),
+        )
+    };
+    // Responses to HEAD have no body to compress.
+    let gzip_min_bytes =
+        gzip_min_bytes.filter(|_| req.method() != Method::HEAD && accepts_gzip(req.headers()));
+    let (mut parts, body) = req.into_parts();
+    if websocket {
+        parts
+            .headers
+            .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        parts
+            .headers
+            .insert(UPGRADE, HeaderValue::from_static("websocket"));
+    }
+    parts.headers.remove(&X_FORCE_BACKEND);
+    // Only the load balancer gets to say why a backend was chosen.
+    parts.headers.remove(&X_LB_DECISION);
+    parts.headers.remove(&X_LB_REASON);
+    add_forwarding_headers(&mut parts.headers, conn_info);
+    // Exact when the client framed the body with Content-Length, None when it was chunked.
+    let declared_len = body.size_hint().exact();
+    let had_content_length = parts.headers.contains_key(CONTENT_LENGTH);
+    if declared_len.is_some_and(|len| len > max_body_bytes) {
+        return Ok(payload_too_large(max_body_bytes));
+    }
+    // A body small enough to look for the model in is buffered for that, even without retries.
+    let peek = model_peek
+        .as_ref()
+        .is_some_and(|&(_, limit)| declared_len.is_some_and(|len| len <= limit));
+    // gRPC streams can be long-lived and bidirectional, and their trailers must get through, so
+    // they are never buffered (and therefore never retried); neither are `stream_paths`.
+    let buffer = (max_retries > 0 || peek) && !is_grpc(&parts.headers) && !stream_path;
+    // Raised once a streamed body has gone past `max_body_bytes`.
+    let mut oversized = None;
+    let (mut streamed_body, buffered_body) = if buffer {
+        match BufferedBody::read(body, max_body_bytes).await {
+            Ok(Some(buffered)) => (None, Some(buffered)),
+            Ok(None) => return Ok(payload_too_large(max_body_bytes)),
+            Err(e) => {
+                debug!(error = %e, status = 400, "failed to read the request body");
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "bad_request",
+                    format!("failed to read the request body: {}", e),
+                    Value::Null,
+                ));
+            }
+        }
+    } else if declared_len == Some(0) {
+        // No body at all; a streamed one would go out chunked.
+        (Some(Body::empty()), None)
+    } else {
+        let (limited, exceeded) = limit_body(body, max_body_bytes);
+        oversized = Some(exceeded);
+        (Some(limited), None)
+    };
+    // The body sent upstream is rebuilt, so its framing is too: Content-Length is set from what
+    // is actually forwarded and hyper picks the transfer encoding (chunked for a streamed body
+    // of unknown length). Transfer-Encoding itself went with the hop-by-hop headers.
+    parts.headers.remove(CONTENT_LENGTH);
+    let forwarded_len = match &buffered_body {
+        Some(BufferedBody { bytes, .. }) => {
+            (had_content_length || !bytes.is_empty()).then_some(bytes.len() as u64)
+        }
+        None => declared_len.filter(|&len| had_content_length || len > 0),
+    };
+    if let Some(len) = forwarded_len {
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    if let Some(max) = body_log {
+        let request_id = request_id_of(&parts.headers);
+        match &buffered_body {
+            Some(BufferedBody { bytes, .. }) => {
+                log_body_prefix("request", &request_id, bytes, bytes.len() as u64, max)
+            }
+            None => {
+                streamed_body = streamed_body.map(|body| log_body(body, "request", request_id, max))
+            }
+        }
+    }
+
+    let model = match (&model_peek, &buffered_body) {
+        (Some((pointer, limit)), Some(BufferedBody { bytes, .. }))
+            if bytes.len() as u64 <= *limit =>
+        {
+            body_model(bytes, pointer)
+        }
+        _ => None,
+    };
+    if let Some(model) = &model {
+        debug!(model = %model, "found the model in the request body");
+    }
+
+    if !wait_for_admission(
+        &app_state,
+        parts.uri.path(),
+        model.as_deref(),
+        forced.as_deref(),
+        priority_offset,
+    )
+    .await
+    {
+        let state = app_state.read().await;
+        let pool_name = state.config.pool_for(parts.uri.path(), model.as_deref());
+        return Ok(all_over_threshold(pool_name, retry_after));
+    }
+
+    // Ids rather than indices, since a config reload may reorder the backends between attempts.
+    let mut tried: Vec<u64> = Vec::new();
+    // Nothing made up here replaces a backend's real answer: a failed retry returns this instead.
+    let mut last_retryable: Option<HeldResponse> = None;
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(match last_retryable.take() {
+                Some(held) => held.relay(tried.len(), gzip_min_bytes),
+                None => deadline_exceeded(started, tried.len()),
+            });
+        }
+        let (
+            backend_id,
+            backend_name,
+            backend_base,
+            protocol,
+            in_flight,
+            breaker,
+            latency,
+            decision,
+            injected,
+        ) = {
+            let state = app_state.read().await;
+            let backends = &state.metrics.backends;
+            let pool_name = state.config.pool_for(parts.uri.path(), model.as_deref());
+            let pool = pool_indices(backends, pool_name);
+            let now = Instant::now();
+            // A forced backend is used no matter its pool or load, and never failed over from.
+            let forced_index = forced
+                .as_deref()
+                .and_then(|name| backends.iter().position(|b| b.name == name));
+            if let Some(index) = forced_index {
+                if tried.is_empty()
+                    && !(backends[index].available() && backends[index].has_capacity())
+                {
+                    let name = &backends[index].name;
+                    warn!(backend = %name, status = 503, "forced backend is unavailable");
+                    let mut resp = error_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "forced_backend_unavailable",
+                        format!(
+                            "backend {:?} from X-Force-Backend can't take requests right now",
+                            name
+                        ),
+                        json!({ "backend": name }),
+                    );
+                    resp.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                    return Ok(resp);
+                }
+            }
+            if state.config.reject_when_all_over_threshold
+                && tried.is_empty()
+                && forced_index.is_none()
+                && pool_saturated(&state.config, backends, &pool, priority_offset)
+            {
+                return Ok(all_over_threshold(pool_name, retry_after));
+            }
+            let shed_mark = state
+                .config
+                .shed_high_water_mark
+                .filter(|_| tried.is_empty() && forced_index.is_none());
+            if let Some(mark) = shed_mark {
+                let chance =
+                    shed_probability(backends, &pool, mark, state.config.shed_max_probability);
+                if chance > 0.0 && lock(&state.rng).gen_bool(chance) {
+                    // Debug only: under overload a steady share of the requests is shed.
+                    debug!(
+                        pool = pool_name,
+                        chance,
+                        status = 503,
+                        "pool is over its high-water mark, shedding"
+                    );
+                    let mut resp = error_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "load_shed",
+                        "the backends of this pool are overloaded and this request was shed; retry later",
+                        json!({ "pool": pool_name, "retry_after_secs": retry_after }),
+                    );
+                    resp.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                    return Ok(resp);
+                }
+            }
+            if tried.is_empty() && forced_index.is_none() && pool_capped(backends, &pool) {
+                // Debug only: while the pool is saturated this fires for every request.
+                debug!(
+                    pool = pool_name,
+                    status = 503,
+                    "every backend is at its max_concurrency, rejecting"
+                );
+                let mut resp = error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "all_backends_at_capacity",
+                    "every backend in this pool is at its max_concurrency; retry later",
+                    json!({ "pool": pool_name, "retry_after_secs": retry_after }),
+                );
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return Ok(resp);
+            }
+            // A failover goes to a backend that hasn't failed this request yet and, with
+            // `max_failover_in_flight`, isn't already taking its share of other failovers.
+            let failover_limit = (!tried.is_empty()).then_some(state.config.max_failover_in_flight);
+            // With a `default_backend`, backends are only trusted once their first scrape is in.
+            let default_backend = state.config.default_backend.as_deref();
+            let trusted = |b: &Backend| {
+                default_backend.is_none_or(|name| b.name == name) || !b.awaiting_metrics()
+            };
+            let eligible = |b: &Backend| {
+                !tried.contains(&b.id)
+                    && failover_limit.is_none_or(|limit| b.takes_failover(limit))
+                    && trusted(b)
+            };
+            let sticky = session_id
+                .as_deref()
+                .filter(|_| forced_index.is_none())
+                .and_then(|session| {
+                    let mut sessions = lock(&state.sessions);
+                    let assigned = sessions.get(session, now)?;
+                    pool.iter().copied().find(|&i| {
+                        let b = &backends[i];
+                        b.name == assigned && b.available() && b.has_capacity() && eligible(b)
+                    })
+                });
+            let req = RequestContext {
+                method: &parts.method,
+                path: parts.uri.path(),
+                headers: &parts.headers,
+                pool: pool_name,
+                attempt: tried.len(),
+            };
+            let views: Vec<BackendView<'_>> = pool
+                .iter()
+                .map(|&i| {
+                    view_of(
+                        &state.config,
+                        &backends[i],
+                        eligible(&backends[i]),
+                        priority_offset,
+                    )
+                })
+                .collect();
+            let selected = match forced_index {
+                Some(index) if tried.is_empty() => Some(index),
+                Some(_) => None,
+                None => sticky.or_else(|| {
+                    let mut rng = lock(&state.rng);
+                    select_backend(
+                        &*state.selector,
+                        backends,
+                        &pool,
+                        views.clone(),
+                        &req,
+                        &mut *rng,
+                    )
+                }),
+            };
+            // Nothing in the pool is both known and usable yet: the default backend stands in,
+            // whatever its pool.
+            let default_index = match (selected, forced_index, default_backend) {
+                (None, None, Some(name))
+                    if pool.iter().any(|&i| backends[i].awaiting_metrics()) =>
+                {
+                    backends.iter().position(|b| {
+                        b.name == name && b.available() && b.has_capacity() && eligible(b)
+                    })
+                }
+                _ => None,
+            };
+            let selected = selected.or(default_index);
+            if let (Some(shadow), Some(active), true, None, None) = (
+                &state.shadow_selector,
+                selected,
+                tried.is_empty(),
+                sticky,
+                forced_index,
+            ) {
+                // Its own randomness too: a seeded `rng` must keep producing the same real routing.
+                let shadow_choice = select_backend(
+                    shadow,
+                    backends,
+                    &pool,
+                    views,
+                    &req,
+                    &mut rand::thread_rng(),
+                );
+                let shadow = shadow.strategy();
+                let agree = shadow_choice == Some(active);
+                let shadow_backend = shadow_choice.map(|i| backends[i].name.as_str());
+                lb_metrics
+                    .shadow_decisions_total
+                    .with_label_values(&[if agree { "agree" } else { "disagree" }])
+                    .inc();
+                if agree {
+                    debug!(backend = %backends[active].name, shadow = shadow.as_str(), "shadow strategy agrees");
+                } else {
+                    info!(
+                        active = %backends[active].name,
+                        shadow_backend = shadow_backend.unwrap_or("none"),
+                        shadow = shadow.as_str(),
+                        "shadow strategy would route elsewhere"
+                    );
+                }
+            }
+            match selected {
+                Some(index) => {
+                    let backend = &backends[index];
+                    let in_flight =
+                        match InFlightGuard::new(backend, &state.load_released, failover_limit) {
+                            Some(guard) => guard,
+                            // Another request took its last permit (or failover slot) in
+                            // the meantime; pick again.
+                            None => continue,
+                        };
+                    let primary = pool.first().copied().unwrap_or(index);
+                    if let (Some(session), None, None) = (&session_id, sticky, forced_index) {
+                        lock(&state.sessions).assign(session, &backend.name, now);
+                    }
+                    let decision = if forced_index.is_some() {
+                        "forced"
+                    } else if !tried.is_empty() {
+                        "failover"
+                    } else if sticky.is_some() {
+                        "session"
+                    } else if default_index.is_some() {
+                        "default_backend"
+                    } else if state.selector.name() != RoutingStrategy::Threshold.as_str() {
+                        state.selector.name()
+                    } else if index == primary {
+                        "primary"
+                    } else if !backends[primary].available() {
+                        "primary_offline"
+                    } else {
+                        "primary_over_threshold"
+                    };
+                    info!(
+                        backend = %backend.name,
+                        pool = pool_name,
+                        kv_ratio = backend.kv_ratio,
+                        pressure = backend.pressure,
+                        decision,
+                        "routing request"
+                    );
+                    tried.push(backend.id);
+                    lock(&backend.breaker).on_dispatch(Instant::now());
+                    // Chaos testing's own randomness, like the shadow strategy's.
+                    let injected = backend
+                        .injected_failure
+                        .is_some_and(|failure| failure.strikes(now, &mut rand::thread_rng()));
+                    (
+                        backend.id,
+                        backend.name.clone(),
+                        backend.base_uri.clone(),
+                        backend.protocol,
+                        in_flight,
+                        backend.breaker.clone(),
+                        backend.latency.clone(),
+                        decision,
+                        injected,
+                    )
+                }
+                None if tried.is_empty() => {
+                    warn!(pool = pool_name, status = 503, "no backend is online");
+                    let mut resp = error_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "no_backend_available",
+                        "no backend in this pool is online, healthy and enabled; retry later",
+                        json!({ "pool": pool_name, "retry_after_secs": retry_after }),
+                    );
+                    resp.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                    return Ok(resp);
+                }
+                None => {
+                    if let Some(held) = last_retryable.take() {
+                        return Ok(held.relay(tried.len(), gzip_min_bytes));
+                    }
+                    warn!(status = 502, attempts = tried.len(), "all backends failed");
+                    return Ok(bad_gateway(tried.len()));
+                }
+            }
+        };
+
+        let uri = forward_uri(&backend_base, &parts.uri);
+        let mut builder = Request::builder().method(parts.method.clone()).uri(uri);
+        let upstream_host = match (host_header, backend_base.authority()) {
+            (HostHeader::Upstream, Some(authority)) => {
+                HeaderValue::from_str(authority.as_str()).ok()
+            }
+            _ => None,
+        };
+        for (key, value) in parts
+            .headers
+            .iter()
+            .filter(|&(key, _)| upstream_host.is_none() || key != HOST)
+        {
+            builder = builder.header(key, value);
+        }
+        if let Some(host) = upstream_host {
+            builder = builder.header(HOST, host);
+        }
+        // Tell the backend why it was picked; names that aren't valid header values are left out.
+        if let Ok(name) = HeaderValue::from_str(&backend_name) {
+            builder = builder.header(X_LB_DECISION, name);
+        }
+        builder = builder.header(X_LB_REASON, decision);
+        let body = match &buffered_body {
+            Some(buffered) => buffered.body(),
+            None => streamed_body.take().unwrap_or_else(Body::empty),
+        };
+        let new_req = match builder.body(body) {
+            Ok(new_req) => new_req,
+            Err(e) => {
+                error!(backend = %backend_name, error = %e, status = 500, "failed to build backend request");
+                return Ok(error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    "failed to build the backend request",
+                    Value::Null,
+                ));
+            }
+        };
+
+        lb_metrics
+            .requests_total
+            .with_label_values(&[&backend_name])
+            .inc();
+        let dispatched = Instant::now();
+        let pending = PendingForward::new(&backend_name, &breaker);
+        // The response headers are due within the backend timeout and the response timeout,
+        // or whatever is left of the request's budget if that's less.
+        let header_timeout = response_timeout.map_or(backend_timeout, |t| t.min(backend_timeout));
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(dispatched));
+        let attempt_timeout =
+            remaining.map_or(header_timeout, |remaining| remaining.min(header_timeout));
+        // A shorter first-byte timeout gives up on the backend early, to fail over.
+        let first_byte = first_byte_timeout.filter(|&limit| limit < attempt_timeout);
+        let limit = first_byte.unwrap_or(attempt_timeout);
+        // Upgrades only exist in HTTP/1.1.
+        let client = if websocket {
+            &clients.http1
+        } else {
+            clients.get(protocol)
+        };
+        let result = if injected {
+            debug!(backend = %backend_name, "injecting a failure instead of forwarding");
+            Ok(Ok(injected_failure()))
+        } else {
+            timeout(limit, client.request(new_req)).await
+        };
+        pending.disarm();
+        // The backend got a truncated body, so whatever it made of that, the client gets the
+        // 413 it would have had for a buffered body. Not the backend's failure either way.
+        if oversized
+            .as_ref()
+            .is_some_and(|exceeded| exceeded.load(Ordering::Acquire))
+        {
+            lock(&breaker).abandon_probe();
+            return Ok(payload_too_large(max_body_bytes));
+        }
+        let mut resp = match result {
+            Ok(Ok(resp)) => {
+                debug!(backend = %backend_name, status = resp.status().as_u16(), "backend responded");
+                let timer = LatencyTimer {
+                    histogram: (!injected).then(|| latency.clone()),
+                    dispatched,
+                    window: latency_window,
+                };
+                let status = resp.status().as_u16();
+                if let Some(delay) = requested_backoff(&resp, SystemTime::now()) {
+                    warn!(backend = %backend_name, status, backoff_secs = delay.as_secs(), "backend asked to back off");
+                    let mut state = app_state.write().await;
+                    if let Some(backend) = state
+                        .metrics
+                        .backends
+                        .iter_mut()
+                        .find(|b| b.id == backend_id)
+                    {
+                        backend.backoff_until = Some(Instant::now() + delay);
+                    }
+                }
+                // Only a buffered body can be replayed to the next backend.
+                if retry_on_status.contains(&status)
+                    && buffered_body.is_some()
+                    && tried.len() <= max_retries
+                {
+                    warn!(backend = %backend_name, status, "backend returned a retryable status");
+                    record_outcome(&breaker, &backend_name, false, breaker_settings);
+                    timer.finish();
+                    last_retryable = Some(HeldResponse {
+                        resp,
+                        in_flight,
+                        backend: backend_name,
+                        deadline: response_timeout.map(|limit| dispatched + limit),
+                    });
+                    continue;
+                }
+                let success = !resp.status().is_server_error();
+                record_outcome(&breaker, &backend_name, success, breaker_settings);
+                let is_error = resp.status().is_client_error() || resp.status().is_server_error();
+                let switched = resp.status() == StatusCode::SWITCHING_PROTOCOLS;
+                if let Some(client_upgrade) = client_upgrade.take().filter(|_| switched) {
+                    // The upgraded connection lasts as long as it is used, so only the
+                    // handshake counts.
+                    timer.finish();
+                    relay_upgraded(resp, client_upgrade, in_flight, backend_name.clone())
+                } else if normalize_errors && is_error && !is_grpc(resp.headers()) {
+                    let resp = normalize_error_body(resp, &backend_name).await;
+                    drop(in_flight);
+                    timer.finish();
+                    resp
+                } else {
+                    let deadline = response_timeout.map(|limit| dispatched + limit);
+                    let resp = stream_with_guard(resp, in_flight, &backend_name, deadline, timer);
+                    match gzip_min_bytes {
+                        Some(min_bytes) => gzip_response(resp, min_bytes),
+                        None => resp,
+                    }
+                }
+            }
+            // Only connection failures are retried: the backend never saw the request. A streamed
+            // body was consumed by the failed attempt, though.
+            Ok(Err(e))
+                if e.is_connect() && buffered_body.is_some() && tried.len() <= max_retries =>
+            {
+                warn!(backend = %backend_name, error = %e, "connection to backend failed");
+                record_outcome(&breaker, &backend_name, false, breaker_settings);
+                continue;
+            }
+            Ok(Err(e)) => {
+                warn!(backend = %backend_name, error = %e, status = 502, "request to backend failed");
+                record_outcome(&breaker, &backend_name, false, breaker_settings);
+                match last_retryable.take() {
+                    Some(held) => return Ok(held.relay(tried.len(), gzip_min_bytes)),
+                    None => bad_gateway(tried.len()),
+                }
+            }
+            // Like a refused connection, a backend that hasn't started answering gets the request
+            // replayed elsewhere.
+            Err(_)
+                if first_byte.is_some()
+                    && buffered_body.is_some()
+                    && tried.len() <= max_retries =>
+            {
+                warn!(backend = %backend_name, "backend sent no response within first_byte_timeout_secs");
+                record_outcome(&breaker, &backend_name, false, breaker_settings);
+                continue;
+            }
+            // Cut short by the request's budget rather than the backend's own timeout, so not
+            // held against the backend.
+            Err(_) if first_byte.is_none() && attempt_timeout < header_timeout => {
+                match last_retryable.take() {
+                    Some(held) => return Ok(held.relay(tried.len(), gzip_min_bytes)),
+                    None => deadline_exceeded(started, tried.len()),
+                }
+            }
+            Err(_) => {
+                warn!(backend = %backend_name, status = 504, "backend timed out");
+                record_outcome(&breaker, &backend_name, false, breaker_settings);
+                if let Some(held) = last_retryable.take() {
+                    return Ok(held.relay(tried.len(), gzip_min_bytes));
+                }
+                error_response(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "backend_timeout",
+                    format!("backend sent no response within {}s", limit.as_secs()),
+                    json!({ "backend": backend_name }),
+                )
+            }
+        };
+        resp.extensions_mut().insert(RoutedTo {
+            backend: backend_name,
+            attempts: tried.len(),
+        });
+        return Ok(resp);
+    }
+}
+
+/// How long a 429 or 503 response asks for the backend to be left alone, from its
+/// `Retry-After` in seconds or as an HTTP date, at most `MAX_BACKOFF`.
+fn requested_backoff(resp: &Response<Body>, now: SystemTime) -> Option<Duration> {
+    if !matches!(
+        resp.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        // A date in the past means no backoff.
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(now)
+            .ok()?,
+    };
+    (!delay.is_zero()).then(|| delay.min(MAX_BACKOFF))
+}
+
+/// What a backend seems to answer while a failure is injected into it: a plain 503, handled
+/// like one the backend sent itself.
+fn injected_failure() -> Response<Body> {
+    let mut resp = Response::new(Body::from("failure injected by the load balancer"));
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    resp
+}
+
+/// The string at `pointer` in a JSON `body`, naming the model the request is for.
+fn body_model(body: &[u8], pointer: &str) -> Option<String> {
+    let body: Value = serde_json::from_slice(body).ok()?;
+    body.pointer(pointer)?.as_str().map(str::to_string)
+}
+
+/// Whether the request is a WebSocket handshake: `Upgrade: websocket` with the `Connection`
+/// header listing `upgrade`.
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let upgrades_connection = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    upgrades_connection
+        && headers
+            .get(UPGRADE)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+/// Passes a backend's `101 Switching Protocols` on to the client and, once both connections
+/// have switched, relays bytes between them until either side closes. The backend counts the
+/// connection as in flight for as long as it lasts.
+fn relay_upgraded(
+    mut resp: Response<Body>,
+    client: OnUpgrade,
+    in_flight: InFlightGuard,
+    backend: String,
+) -> Response<Body> {
+    let upstream = hyper::upgrade::on(&mut resp);
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        match tokio::try_join!(client, upstream) {
+            Ok((mut client, mut upstream)) => {
+                match copy_bidirectional(&mut client, &mut upstream).await {
+                    Ok((sent, received)) => {
+                        debug!(backend = %backend, sent, received, "upgraded connection closed")
+                    }
+                    Err(e) => debug!(backend = %backend, error = %e, "upgraded connection broke"),
+                }
+            }
+            Err(e) => warn!(backend = %backend, error = %e, "connection upgrade failed"),
+        }
+    });
+    resp
+}
+
+/// Indices of the backends belonging to `pool_name`, in configured order.
+fn pool_indices(backends: &[Backend], pool_name: &str) -> Vec<usize> {
+    (0..backends.len())
+        .filter(|&i| backends[i].pool == pool_name)
+        .collect()
+}
+
+/// What the selector sees of `backend`, with the shedding state for `priority_offset`.
+fn view_of<'a>(
+    config: &LbConfig,
+    backend: &'a Backend,
+    eligible: bool,
+    priority_offset: f64,
+) -> BackendView<'a> {
+    let mut view = backend.view(eligible);
+    view.shedding = backend.sheds_for(config.capacity_threshold_of(&backend.name), priority_offset);
+    view.tier = config.tier_rank_of(&backend.name);
+    view
+}
+
+/// The backend of `pool_name` the selector would pick for a new `GET` of `path` right now, for
+/// `GET /admin/recommend`; None when no backend could take it. Nothing is sent, though the
+/// warmup ramp isn't rolled for and rotation among tied backends moves on a step.
+pub(crate) fn recommend<'a>(
+    state: &'a AppState,
+    pool_name: &str,
+    path: &str,
+) -> Option<&'a Backend> {
+    let backends = &state.metrics.backends;
+    let pool = pool_indices(backends, pool_name);
+    let default_backend = state.config.default_backend.as_deref();
+    let views: Vec<BackendView<'_>> = pool
+        .iter()
+        .map(|&i| {
+            let b = &backends[i];
+            let trusted =
+                default_backend.is_none_or(|name| b.name == name) || !b.awaiting_metrics();
+            view_of(&state.config, b, trusted, 0.0)
+        })
+        .collect();
+    let headers = HeaderMap::new();
+    let req = RequestContext {
+        method: &Method::GET,
+        path,
+        headers: &headers,
+        pool: pool_name,
+        attempt: 0,
+    };
+    let choice = checked_select(&*state.selector, &views, &req)?;
+    Some(&backends[pool[choice]])
+}
+
+/// Whether every usable backend among `pool` is shedding load; false when none is usable.
+/// `priority_offset` shifts the thresholds as for `X-Priority`.
+fn pool_saturated(
+    config: &LbConfig,
+    backends: &[Backend],
+    pool: &[usize],
+    priority_offset: f64,
+) -> bool {
+    let mut usable = pool
+        .iter()
+        .map(|&i| &backends[i])
+        .filter(|b| b.available())
+        .peekable();
+    usable.peek().is_some()
+        && usable.all(|b| b.sheds_for(config.capacity_threshold_of(&b.name), priority_offset))
+}
+
+/// Chance of shedding a request for `pool`: 0 up to the high-water `mark` of its usable
+/// backends' average pressure, rising linearly to `max` at full pressure. 0 when none is usable.
+fn shed_probability(backends: &[Backend], pool: &[usize], mark: f64, max: f64) -> f64 {
+    let pressures: Vec<f64> = pool
+        .iter()
+        .map(|&i| &backends[i])
+        .filter(|b| b.available())
+        .map(|b| b.load())
+        .collect();
+    if pressures.is_empty() {
+        return 0.0;
+    }
+    let average = pressures.iter().sum::<f64>() / pressures.len() as f64;
+    (((average - mark) / (1.0 - mark)).clamp(0.0, 1.0) * max).clamp(0.0, 1.0)
+}
+
+/// Whether every usable backend among `pool` is at its `max_concurrency`; false when none is
+/// usable.
+fn pool_capped(backends: &[Backend], pool: &[usize]) -> bool {
+    let mut usable = pool
+        .iter()
+        .map(|&i| &backends[i])
+        .filter(|b| b.available())
+        .peekable();
+    usable.peek().is_some() && usable.all(|b| !b.has_capacity())
+}
+
+/// Whether a request for `pool` has to wait for, or be rejected until, a backend frees up.
+fn pool_blocked(
+    config: &LbConfig,
+    backends: &[Backend],
+    pool: &[usize],
+    priority_offset: f64,
+) -> bool {
+    (config.reject_when_all_over_threshold
+        && pool_saturated(config, backends, pool, priority_offset))
+        || pool_capped(backends, pool)
+}
+
+fn all_over_threshold(pool: &str, retry_after: u64) -> Response<Body> {
+    // Debug only: under overload this would fire for every request.
+    debug!(
+        pool,
+        status = 503,
+        "every backend is over its threshold, rejecting"
+    );
+    let mut resp = error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "all_backends_over_threshold",
+        "every backend in this pool is over its capacity threshold; retry later",
+        json!({ "pool": pool, "retry_after_secs": retry_after }),
+    );
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    resp
+}
+
+/// When the pool serving `path` is blocked (see `pool_blocked`) and the admission queue has
+/// room, holds the request until a backend drops below its threshold or finishes a request,
+/// or `admission_max_wait_secs` pass. Returns false if the request should be rejected right
+/// away because the queue is full; a request that waited in vain is rejected by
+/// `forward_request`'s own checks.
+async fn wait_for_admission(
+    app_state: &RwLock<AppState>,
+    path: &str,
+    model: Option<&str>,
+    forced: Option<&str>,
+    priority_offset: f64,
+) -> bool {
+    let (admission, load_released, max_wait) = {
+        let state = app_state.read().await;
+        let config = &state.config;
+        let backends = &state.metrics.backends;
+        let forced_known = forced.is_some_and(|name| backends.iter().any(|b| b.name == name));
+        if config.admission_queue_depth == 0 || forced_known {
+            return true;
+        }
+        let pool = pool_indices(backends, config.pool_for(path, model));
+        if !pool_blocked(config, backends, &pool, priority_offset) {
+            return true;
+        }
+        (
+            state.admission.clone(),
+            state.load_released.clone(),
+            Duration::from_secs(config.admission_max_wait_secs),
+        )
+    };
+    let _permit = match admission.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => return false,
+    };
+    let deadline = Instant::now() + max_wait;
+    loop {
+        let released = load_released.notified();
+        {
+            let state = app_state.read().await;
+            let pool = pool_indices(&state.metrics.backends, state.config.pool_for(path, model));
+            if !pool_blocked(
+                &state.config,
+                &state.metrics.backends,
+                &pool,
+                priority_offset,
+            ) {
+                return true;
+            }
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || timeout(remaining, released).await.is_err() {
+            return true;
+        }
+    }
+}
+
+fn deadline_exceeded(started: Instant, attempts: usize) -> Response<Body> {
+    warn!(
+        attempts,
+        elapsed_secs = started.elapsed().as_secs_f64(),
+        status = 504,
+        "request deadline exceeded"
+    );
+    error_response(
+        StatusCode::GATEWAY_TIMEOUT,
+        "request_deadline_exceeded",
+        "no backend responded within the request deadline",
+        json!({ "attempts": attempts }),
+    )
+}
+
+fn bad_gateway(attempts: usize) -> Response<Body> {
+    error_response(
+        StatusCode::BAD_GATEWAY,
+        "bad_gateway",
+        "the request to the backend failed",
+        json!({ "attempts": attempts }),
+    )
+}
+
+pub(crate) fn payload_too_large(limit: u64) -> Response<Body> {
+    warn!(limit, status = 413, "request body too large");
+    error_response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        format!("the request body is larger than {} bytes", limit),
+        json!({ "max_body_bytes": limit }),
+    )
+}
+
+/// Buffers a request body, giving up with `None` as soon as it exceeds `limit` bytes.
+pub(crate) async fn read_body_limited(
+    mut body: Body,
+    limit: u64,
+) -> Result<Option<Bytes>, hyper::Error> {
+    read_data_limited(&mut body, limit).await
+}
+
+async fn read_data_limited(body: &mut Body, limit: u64) -> Result<Option<Bytes>, hyper::Error> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (buffer.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(Some(Bytes::from(buffer)))
+}
+
+/// A request body read into memory so it can be sent again, with the trailers that followed it
+/// (which only HTTP/2 clients can send).
+struct BufferedBody {
+    bytes: Bytes,
+    trailers: Option<HeaderMap>,
+}
+
+impl BufferedBody {
+    /// Like `read_body_limited`, keeping the trailers.
+    async fn read(mut body: Body, limit: u64) -> Result<Option<Self>, hyper::Error> {
+        let bytes = match read_data_limited(&mut body, limit).await? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let trailers = body.trailers().await?;
+        Ok(Some(BufferedBody { bytes, trailers }))
+    }
+
+    /// A fresh copy of the body to forward, trailers included.
+    fn body(&self) -> Body {
+        let trailers = match &self.trailers {
+            Some(trailers) => trailers.clone(),
+            None => return Body::from(self.bytes.clone()),
+        };
+        let (mut sender, body) = Body::channel();
+        let bytes = self.bytes.clone();
+        tokio::spawn(async move {
+            if !bytes.is_empty() && sender.send_data(bytes).await.is_err() {
+                return;
+            }
+            let _ = sender.send_trailers(trailers).await;
+        });
+        body
+    }
+}
+
+/// Streams a request body through, aborting it once more than `limit` bytes have passed. By
+/// then the request is already on its way to the backend, which sees a truncated body, so the
+/// returned flag is raised first to tell the failed attempt apart from a backend failure.
+fn limit_body(mut body: Body, limit: u64) -> (Body, Arc<AtomicBool>) {
+    let (mut sender, limited) = Body::channel();
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let raised = exceeded.clone();
+    tokio::spawn(async move {
+        let mut seen: u64 = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            seen += chunk.len() as u64;
+            if seen > limit {
+                warn!(limit, "streamed request body too large, aborting it");
+                raised.store(true, Ordering::Release);
+                return sender.abort();
+            }
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    (limited, exceeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::MetricsState;
+    use crate::selector::StrategySelector;
+    use flate2::read::GzDecoder;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::io::Read;
+
+    /// One backend per pressure, named `b0`, `b1`, ... in order of preference. `extra` is
+    /// appended to every backend's table.
+    fn backends_with(pressures: &[f64], extra: &str) -> Vec<Backend> {
+        let text: String = (0..pressures.len())
+            .map(|i| {
+                format!(
+                    "[[backends]]\nname = \"b{}\"\nbase_uri = \"http://127.0.0.1:{}\"\n{}\n",
+                    i,
+                    9000 + i,
+                    extra
+                )
+            })
+            .collect();
+        let config = LbConfig::from_toml(&text).expect("valid test config");
+        let mut backends = MetricsState::new(&config.backends).unwrap().backends;
+        for (backend, &pressure) in backends.iter_mut().zip(pressures) {
+            backend.kv_ratio = pressure;
+            backend.pressure = pressure;
+        }
+        backends
+    }
+
+    /// Where `least_loaded` routes the first attempt at a request, having tried `tried`.
+    fn select(backends: &[Backend], tried: &[u64]) -> Option<usize> {
+        let pool: Vec<usize> = (0..backends.len()).collect();
+        let views = pool
+            .iter()
+            .map(|&i| backends[i].view(!tried.contains(&backends[i].id)))
+            .collect();
+        let headers = HeaderMap::new();
+        let req = RequestContext {
+            method: &Method::GET,
+            path: "/",
+            headers: &headers,
+            pool: "default",
+            attempt: tried.len(),
+        };
+        let selector = StrategySelector::new(RoutingStrategy::LeastLoaded, None, 0.0);
+        select_backend(
+            &selector,
+            backends,
+            &pool,
+            views,
+            &req,
+            &mut StdRng::seed_from_u64(7),
+        )
+    }
+
+    #[test]
+    fn offline_and_tried_backends_are_skipped() {
+        let mut backends = backends_with(&[0.3, 0.1, 0.2], "");
+        backends[1].online = false;
+        assert_eq!(select(&backends, &[]), Some(2));
+        assert_eq!(select(&backends, &[backends[2].id]), Some(0));
+    }
+
+    #[test]
+    fn stale_backends_count_as_full() {
+        let mut backends = backends_with(&[0.1, 0.5], "");
+        backends[0].stale = true;
+        assert_eq!(select(&backends, &[]), Some(1));
+    }
+
+    #[test]
+    fn backed_off_backends_are_skipped_until_the_time_is_up() {
+        let mut backends = backends_with(&[0.1, 0.5], "");
+        backends[0].backoff_until = Some(Instant::now() + Duration::from_secs(60));
+        assert_eq!(select(&backends, &[]), Some(1));
+        backends[0].backoff_until = Some(Instant::now());
+        assert_eq!(select(&backends, &[]), Some(0));
+    }
+
+    #[test]
+    fn shedding_grows_with_the_average_pressure_over_the_mark() {
+        let mut backends = backends_with(&[0.8, 1.0, 0.1], "");
+        backends[2].online = false;
+        let pool = [0, 1, 2];
+        assert!((shed_probability(&backends, &pool, 0.8, 1.0) - 0.5).abs() < 1e-9);
+        assert!((shed_probability(&backends, &pool, 0.8, 0.5) - 0.25).abs() < 1e-9);
+        assert_eq!(shed_probability(&backends, &pool, 0.95, 1.0), 0.0);
+        assert_eq!(shed_probability(&backends, &[2], 0.0, 1.0), 0.0);
+    }
+
+    fn answer(status: StatusCode, retry_after: &str) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(RETRY_AFTER, retry_after)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds() {
+        let now = SystemTime::now();
+        let backoff = |status, value| requested_backoff(&answer(status, value), now);
+        assert_eq!(
+            backoff(StatusCode::TOO_MANY_REQUESTS, "5"),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            backoff(StatusCode::SERVICE_UNAVAILABLE, " 120 "),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            backoff(StatusCode::TOO_MANY_REQUESTS, "86400"),
+            Some(MAX_BACKOFF)
+        );
+        assert_eq!(backoff(StatusCode::TOO_MANY_REQUESTS, "0"), None);
+        assert_eq!(backoff(StatusCode::TOO_MANY_REQUESTS, "soon"), None);
+        // Only overload responses make a backend back off.
+        assert_eq!(backoff(StatusCode::BAD_GATEWAY, "5"), None);
+    }
+
+    #[test]
+    fn retry_after_is_read_as_an_http_date() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2026 07:28:00 GMT").unwrap();
+        let backoff = |value| requested_backoff(&answer(StatusCode::TOO_MANY_REQUESTS, value), now);
+        assert_eq!(
+            backoff("Wed, 21 Oct 2026 07:28:30 GMT"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(backoff("Wed, 21 Oct 2026 09:00:00 GMT"), Some(MAX_BACKOFF));
+        assert_eq!(backoff("Wed, 21 Oct 2026 07:27:00 GMT"), None);
+    }
+
+    #[test]
+    fn backends_at_max_concurrency_are_skipped() {
+        let backends = backends_with(&[0.1, 0.5], "max_concurrency = 1");
+        let permit = backends[0]
+            .concurrency
+            .as_ref()
+            .unwrap()
+            .clone()
+            .try_acquire_owned()
+            .unwrap();
+        assert_eq!(select(&backends, &[]), Some(1));
+        drop(permit);
+        assert_eq!(select(&backends, &[]), Some(0));
+    }
+
+    #[test]
+    fn failovers_to_one_backend_are_capped() {
+        let backends = backends_with(&[0.1], "");
+        let released = Arc::new(Notify::new());
+        let first = InFlightGuard::new(&backends[0], &released, Some(1)).expect("below the cap");
+        assert!(InFlightGuard::new(&backends[0], &released, Some(1)).is_none());
+        assert!(!backends[0].takes_failover(1));
+        // Requests that didn't fail over aren't limited.
+        let direct = InFlightGuard::new(&backends[0], &released, None).expect("not a failover");
+        assert_eq!(backends[0].in_flight(), 2);
+        drop(first);
+        assert!(backends[0].takes_failover(1));
+        drop(direct);
+        assert_eq!(backends[0].in_flight(), 0);
+    }
+
+    /// Picks whichever backend it is told to, available or not.
+    struct Fixed(usize);
+
+    impl Selector for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn select(
+            &self,
+            _backends: &[BackendView<'_>],
+            _req: &RequestContext<'_>,
+        ) -> Option<usize> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn custom_selectors_cannot_pick_unavailable_backends() {
+        let mut backends = backends_with(&[0.1, 0.5], "");
+        backends[1].online = false;
+        let pool = [0, 1];
+        let headers = HeaderMap::new();
+        let req = RequestContext {
+            method: &Method::GET,
+            path: "/",
+            headers: &headers,
+            pool: "default",
+            attempt: 0,
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+        for (choice, expected) in [(0, Some(0)), (1, None), (7, None)] {
+            let views = backends.iter().map(|b| b.view(true)).collect();
+            assert_eq!(
+                select_backend(&Fixed(choice), &backends, &pool, views, &req, &mut rng),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn the_model_is_read_from_the_body_at_the_pointer() {
+        assert_eq!(
+            body_model(br#"{"model":"llama-70b","prompt":"hi"}"#, "/model").as_deref(),
+            Some("llama-70b")
+        );
+        assert_eq!(
+            body_model(br#"{"params":{"model":"small"}}"#, "/params/model").as_deref(),
+            Some("small")
+        );
+        assert_eq!(body_model(br#"{"model":7}"#, "/model"), None);
+        assert_eq!(body_model(br#"{"prompt":"hi"}"#, "/model"), None);
+        assert_eq!(body_model(b"not json", "/model"), None);
+    }
+
+    #[test]
+    fn outcomes_follow_the_error_code_and_attempts() {
+        let routed = |attempts| {
+            let mut resp = Response::new(Body::empty());
+            resp.extensions_mut().insert(RoutedTo {
+                backend: "b0".to_string(),
+                attempts,
+            });
+            resp
+        };
+        assert_eq!(Outcome::of(&routed(1)), Outcome::Routed);
+        assert_eq!(Outcome::of(&routed(2)), Outcome::Failover);
+        assert_eq!(
+            Outcome::of(&all_over_threshold("default", 5)),
+            Outcome::NoBackend
+        );
+        assert_eq!(Outcome::of(&payload_too_large(10)), Outcome::InvalidRequest);
+        // The error code wins over the backend the request was sent to.
+        let mut timed_out = error_response(
+            StatusCode::GATEWAY_TIMEOUT,
+            "backend_timeout",
+            "slow",
+            Value::Null,
+        );
+        timed_out.extensions_mut().insert(RoutedTo {
+            backend: "b0".to_string(),
+            attempts: 1,
+        });
+        assert_eq!(Outcome::of(&timed_out), Outcome::Timeout);
+    }
+
+    #[test]
+    fn gzip_is_accepted_unless_refused() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip, deflate, br"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("deflate, br"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn only_plain_sized_responses_are_gzipped() {
+        let text = "token ".repeat(500);
+        let response = |content_type: &str, sized: bool| {
+            let mut builder = Response::builder().header(CONTENT_TYPE, content_type);
+            if sized {
+                builder = builder.header(CONTENT_LENGTH, text.len());
+            }
+            builder.body(Body::from(text.clone())).unwrap()
+        };
+
+        let resp = gzip_response(response("application/json", true), 1024);
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[VARY], "accept-encoding");
+        assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        // Too small, streamed as server-sent events, or of unknown length.
+        assert!(gzip_response(response("application/json", true), 4096)
+            .headers()
+            .get(CONTENT_ENCODING)
+            .is_none());
+        let events = gzip_response(response("text/event-stream", true), 1024);
+        assert!(events.headers().get(CONTENT_ENCODING).is_none());
+        let chunked = gzip_response(response("application/json", false), 1024);
+        assert!(chunked.headers().get(CONTENT_ENCODING).is_none());
+        let mut encoded = response("application/json", true);
+        encoded
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert_eq!(
+            gzip_response(encoded, 1024).headers()[CONTENT_ENCODING],
+            "br"
+        );
+    }
+
+    #[tokio::test]
+    async fn logged_bodies_pass_through_unchanged() {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("first chunk, ".into()).await.unwrap();
+            sender.send_data("second chunk".into()).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        let mut logged = log_body(body, "response", "id".to_string(), 4);
+        assert_eq!(
+            hyper::body::to_bytes(&mut logged).await.unwrap(),
+            "first chunk, second chunk"
+        );
+        let trailers = logged.trailers().await.unwrap().expect("trailers are kept");
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+
+    #[test]
+    fn upstream_error_messages_come_from_the_usual_fields() {
+        assert_eq!(
+            upstream_error_message(br#"{"error":"model not ready"}"#).as_deref(),
+            Some("model not ready")
+        );
+        assert_eq!(
+            upstream_error_message(br#"{"error":{"message":"context too long","code":400}}"#)
+                .as_deref(),
+            Some("context too long")
+        );
+        assert_eq!(
+            upstream_error_message(br#"{"detail":"not found"}"#).as_deref(),
+            Some("not found")
+        );
+        assert_eq!(
+            upstream_error_message(b"  upstream exploded\n").as_deref(),
+            Some("upstream exploded")
+        );
+        assert_eq!(
+            upstream_error_message(br#"{"code":7}"#).as_deref(),
+            Some(r#"{"code":7}"#)
+        );
+        assert_eq!(upstream_error_message(b" \n"), None);
+    }
+
+    #[tokio::test]
+    async fn error_bodies_are_wrapped_in_the_envelope() {
+        let resp = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(CONTENT_TYPE, "text/plain")
+            .header(CONTENT_LENGTH, "8")
+            .header(RETRY_AFTER, "3")
+            .body(Body::from("too busy"))
+            .unwrap();
+        let resp = normalize_error_body(resp, "b0").await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()[RETRY_AFTER], "3");
+        assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": "too busy", "upstream_status": 503, "backend": "b0" })
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! The routing policy as an extension point: `Selector` picks a backend from read-only views
+//! of a pool, and `StrategySelector` implements the built-in `routing_strategy` values.
+
+use hyper::{HeaderMap, Method};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::config::RoutingStrategy;
+use crate::lock;
+
+/// Pressure scores this close to the lowest one count as equally loaded.
+const PRESSURE_TIE_TOLERANCE: f64 = 0.01;
+
+/// Read-only snapshot of a backend, as a `Selector` sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct BackendView<'a> {
+    pub name: &'a str,
+    /// Whether the request may be sent there: the backend is online, healthy, not drained,
+    /// not backing off after a `Retry-After`, let through by its circuit breaker, below its
+    /// `max_concurrency` and not already tried for this request.
+    pub available: bool,
+    /// Share of the traffic among equally good backends.
+    pub weight: u32,
+    /// KV cache usage ratio from the last scrape.
+    pub kv_ratio: f64,
+    /// Pressure score routing compares, from 0 (idle) to 1; 1 while the metrics are stale.
+    pub pressure: f64,
+    /// Whether the backend went over its capacity threshold and hasn't dropped below its
+    /// release threshold since.
+    pub shedding: bool,
+    /// Unused KV cache blocks; 0 while unknown or stale.
+    pub free_blocks: f64,
+    /// Requests forwarded there whose response hasn't finished streaming yet.
+    pub in_flight: usize,
+    /// Position of the backend's tier in `tiers`, 0 being the best; None without a tier.
+    pub tier: Option<usize>,
+}
+
+/// The request a `Selector` is routing.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestContext<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap,
+    /// Pool serving the request, whose backends the selector chooses from.
+    pub pool: &'a str,
+    /// 0 for the first try, 1 for the first failover and so on.
+    pub attempt: usize,
+}
+
+/// A routing policy. The load balancer calls it for every request that isn't pinned to a
+/// backend by a session or `X-Force-Backend`.
+pub trait Selector: Send + Sync {
+    /// Logged as the routing decision and sent to the backend in `X-LB-Reason`.
+    fn name(&self) -> &'static str;
+
+    /// The index into `backends` (the request's pool, in configured order of preference) to
+    /// forward to, or None to answer 503. Only an `available` backend may be returned.
+    fn select(&self, backends: &[BackendView<'_>], req: &RequestContext<'_>) -> Option<usize>;
+}
+
+/// The built-in policies, as configured by `routing_strategy`.
+pub(crate) struct StrategySelector {
+    strategy: RoutingStrategy,
+    /// Randomness for the weighted strategy; seeded from `rng_seed` when set.
+    rng: Mutex<StdRng>,
+    /// Least weight `weighted_random` gives a backend, from `weight_floor`.
+    weight_floor: f64,
+    /// Rotates through backends that the strategy considers equally good.
+    tie_cursor: AtomicUsize,
+}
+
+impl StrategySelector {
+    pub(crate) fn new(strategy: RoutingStrategy, seed: Option<u64>, weight_floor: f64) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        StrategySelector {
+            strategy,
+            rng: Mutex::new(rng),
+            weight_floor,
+            tie_cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn strategy(&self) -> RoutingStrategy {
+        self.strategy
+    }
+}
+
+impl Selector for StrategySelector {
+    fn name(&self) -> &'static str {
+        self.strategy.as_str()
+    }
+
+    fn select(&self, backends: &[BackendView<'_>], _req: &RequestContext<'_>) -> Option<usize> {
+        let tie_cursor = &self.tie_cursor;
+        let usable = |i: &usize| backends[*i].available;
+        let candidates = || (0..backends.len()).filter(usable);
+        match self.strategy {
+            RoutingStrategy::Threshold if backends.iter().any(|b| b.tier.is_some()) => {
+                // Tier by tier, the least loaded backend that isn't shedding load; backends
+                // without a tier form a last one. Like a lone spill target, the last tier takes
+                // requests even while shedding, and with nowhere else to go anyone will do.
+                let mut ranks: Vec<usize> = backends
+                    .iter()
+                    .map(|b| b.tier.unwrap_or(usize::MAX))
+                    .collect();
+                ranks.sort_unstable();
+                ranks.dedup();
+                let last = ranks.last().copied();
+                ranks
+                    .into_iter()
+                    .find_map(|rank| {
+                        let in_tier = candidates()
+                            .filter(|&i| backends[i].tier.unwrap_or(usize::MAX) == rank);
+                        if Some(rank) == last {
+                            least_loaded(backends, in_tier, tie_cursor)
+                        } else {
+                            least_loaded(
+                                backends,
+                                in_tier.filter(|&i| !backends[i].shedding),
+                                tie_cursor,
+                            )
+                        }
+                    })
+                    .or_else(|| least_loaded(backends, candidates(), tie_cursor))
+            }
+            RoutingStrategy::Threshold => {
+                // The primary (first) backend is used while it is not shedding load; otherwise the
+                // online backend with the lowest pressure wins.
+                let primary = &backends.first()?;
+                if primary.available && !primary.shedding {
+                    return Some(0);
+                }
+                let spill = least_loaded(backends, (1..backends.len()).filter(usable), tie_cursor);
+                // With nowhere to spill, an overloaded primary is still better than nothing.
+                spill.or(if primary.available { Some(0) } else { None })
+            }
+            RoutingStrategy::LeastLoaded => least_loaded(backends, candidates(), tie_cursor),
+            RoutingStrategy::WeightedRandom => {
+                let candidates: Vec<usize> = candidates().collect();
+                let weights: Vec<f64> = candidates
+                    .iter()
+                    .map(|&i| (1.0 - backends[i].pressure).max(self.weight_floor))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                if total <= 0.0 {
+                    // Every candidate is full; fall back to the least bad one.
+                    return least_loaded(backends, candidates.into_iter(), tie_cursor);
+                }
+                let mut point = lock(&self.rng).gen_range(0.0..total);
+                for (&i, &weight) in candidates.iter().zip(&weights) {
+                    if point < weight {
+                        return Some(i);
+                    }
+                    point -= weight;
+                }
+                candidates.last().copied()
+            }
+            RoutingStrategy::LeastConnections => {
+                let fewest = candidates().map(|i| backends[i].in_flight).min()?;
+                let idlest = candidates().filter(|&i| backends[i].in_flight == fewest);
+                least_loaded(backends, idlest, tie_cursor)
+            }
+            RoutingStrategy::FailoverOrder => candidates()
+                .find(|&i| !backends[i].shedding)
+                // Everyone is over the threshold; spread the overload as evenly as we can.
+                .or_else(|| least_loaded(backends, candidates(), tie_cursor)),
+            RoutingStrategy::MostFreeBlocks => {
+                let most = candidates()
+                    .map(|i| backends[i].free_blocks)
+                    .reduce(f64::max)?;
+                let roomiest = candidates().filter(|&i| backends[i].free_blocks >= most);
+                least_loaded(backends, roomiest, tie_cursor)
+            }
+        }
+    }
+}
+
+/// The candidate with the lowest pressure. Candidates within `PRESSURE_TIE_TOLERANCE` of it
+/// share the traffic by weight.
+fn least_loaded(
+    backends: &[BackendView<'_>],
+    candidates: impl Iterator<Item = usize>,
+    tie_cursor: &AtomicUsize,
+) -> Option<usize> {
+    let candidates: Vec<usize> = candidates.collect();
+    let lowest = candidates
+        .iter()
+        .map(|&i| backends[i].pressure)
+        .fold(f64::INFINITY, f64::min);
+    let tied: Vec<usize> = candidates
+        .into_iter()
+        .filter(|&i| backends[i].pressure - lowest <= PRESSURE_TIE_TOLERANCE)
+        .collect();
+    weighted_round_robin(backends, &tied, tie_cursor)
+}
+
+/// Cycles through `candidates`, giving each as many consecutive turns as its weight.
+fn weighted_round_robin(
+    backends: &[BackendView<'_>],
+    candidates: &[usize],
+    cursor: &AtomicUsize,
+) -> Option<usize> {
+    if candidates.len() <= 1 {
+        return candidates.first().copied();
+    }
+    let total: usize = candidates
+        .iter()
+        .map(|&i| backends[i].weight as usize)
+        .sum();
+    let mut turn = cursor.fetch_add(1, Ordering::Relaxed) % total;
+    for &i in candidates {
+        let weight = backends[i].weight as usize;
+        if turn < weight {
+            return Some(i);
+        }
+        turn -= weight;
+    }
+    candidates.last().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One available backend per pressure, shedding above the default capacity threshold.
+    fn views(pressures: &[f64]) -> Vec<BackendView<'static>> {
+        pressures
+            .iter()
+            .map(|&pressure| BackendView {
+                name: "backend",
+                available: true,
+                weight: 1,
+                kv_ratio: pressure,
+                pressure,
+                shedding: pressure >= 0.7,
+                free_blocks: 0.0,
+                in_flight: 0,
+                tier: None,
+            })
+            .collect()
+    }
+
+    fn request(headers: &HeaderMap) -> RequestContext<'_> {
+        RequestContext {
+            method: &Method::POST,
+            path: "/v2/models/ensemble/generate",
+            headers,
+            pool: "default",
+            attempt: 0,
+        }
+    }
+
+    fn select(strategy: RoutingStrategy, backends: &[BackendView<'_>]) -> Option<usize> {
+        let headers = HeaderMap::new();
+        StrategySelector::new(strategy, Some(7), 0.0).select(backends, &request(&headers))
+    }
+
+    #[test]
+    fn threshold_stays_on_primary_until_it_sheds() {
+        let mut backends = views(&[0.5, 0.1, 0.2]);
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(0));
+        backends[0].shedding = true;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(1));
+    }
+
+    #[test]
+    fn threshold_keeps_an_overloaded_primary_with_nowhere_to_spill() {
+        let mut backends = views(&[0.9, 0.1]);
+        backends[1].available = false;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(0));
+        backends[0].available = false;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), None);
+    }
+
+    #[test]
+    fn threshold_fills_the_best_tier_before_spilling() {
+        let mut backends = views(&[0.6, 0.3, 0.2, 0.1]);
+        for (backend, tier) in backends.iter_mut().zip([Some(0), Some(0), Some(1), None]) {
+            backend.tier = tier;
+        }
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(1));
+        backends[1].shedding = true;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(0));
+        backends[0].available = false;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(2));
+        backends[2].shedding = true;
+        backends[3].shedding = true;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(3));
+        backends[3].available = false;
+        assert_eq!(select(RoutingStrategy::Threshold, &backends), Some(2));
+    }
+
+    #[test]
+    fn least_loaded_skips_unavailable_backends() {
+        let mut backends = views(&[0.4, 0.1, 0.2]);
+        assert_eq!(select(RoutingStrategy::LeastLoaded, &backends), Some(1));
+        backends[1].available = false;
+        assert_eq!(select(RoutingStrategy::LeastLoaded, &backends), Some(2));
+    }
+
+    #[test]
+    fn ties_alternate_by_weight() {
+        let mut backends = views(&[0.2, 0.205]);
+        backends[0].weight = 2;
+        let selector = StrategySelector::new(RoutingStrategy::LeastLoaded, None, 0.0);
+        let headers = HeaderMap::new();
+        let req = request(&headers);
+        let picks: Vec<usize> = (0..6)
+            .filter_map(|_| selector.select(&backends, &req))
+            .collect();
+        assert_eq!(picks, [0, 0, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn weighted_random_never_picks_a_full_backend() {
+        let backends = views(&[1.0, 0.6, 0.3]);
+        let selector = StrategySelector::new(RoutingStrategy::WeightedRandom, Some(7), 0.0);
+        let headers = HeaderMap::new();
+        let req = request(&headers);
+        let mut counts = [0; 3];
+        for _ in 0..300 {
+            counts[selector
+                .select(&backends, &req)
+                .expect("a backend is available")] += 1;
+        }
+        assert_eq!(counts[0], 0);
+        assert!(
+            counts[2] > counts[1],
+            "the emptier backend should win more often: {:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn weight_floor_keeps_a_trickle_to_full_backends() {
+        let backends = views(&[1.0, 0.0]);
+        let selector = StrategySelector::new(RoutingStrategy::WeightedRandom, Some(7), 0.1);
+        let headers = HeaderMap::new();
+        let req = request(&headers);
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            counts[selector
+                .select(&backends, &req)
+                .expect("a backend is available")] += 1;
+        }
+        assert!(
+            (40..200).contains(&counts[0]),
+            "about a tenth of the idle backend's share: {:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn least_connections_prefers_the_idlest() {
+        let mut backends = views(&[0.1, 0.5]);
+        backends[0].in_flight = 3;
+        backends[1].in_flight = 1;
+        assert_eq!(
+            select(RoutingStrategy::LeastConnections, &backends),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn failover_order_takes_the_first_backend_under_threshold() {
+        let mut backends = views(&[0.8, 0.6, 0.1]);
+        assert_eq!(select(RoutingStrategy::FailoverOrder, &backends), Some(1));
+        backends[1].shedding = true;
+        assert_eq!(select(RoutingStrategy::FailoverOrder, &backends), Some(2));
+    }
+
+    #[test]
+    fn most_free_blocks_compares_absolute_counts() {
+        let mut backends = views(&[0.5, 0.2]);
+        backends[0].free_blocks = 400.0;
+        backends[1].free_blocks = 100.0;
+        assert_eq!(select(RoutingStrategy::MostFreeBlocks, &backends), Some(0));
+    }
+}
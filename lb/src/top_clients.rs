@@ -0,0 +1,98 @@
+//! Recent request counts per client IP, for spotting the clients sending the most traffic.
+
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::time::Instant;
+use tokio::time::Duration;
+
+/// Most client IPs counted at once; the least recently seen is forgotten beyond this.
+const MAX_COUNTED_CLIENTS: usize = 10_000;
+
+/// A request count that decays exponentially with `ClientCounts::window` as its time
+/// constant, so at a steady rate it settles at about the requests sent within one window.
+struct DecayingCount {
+    count: f64,
+    updated_at: Instant,
+}
+
+impl DecayingCount {
+    fn at(&self, now: Instant, window: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.count * (-elapsed / window.as_secs_f64()).exp()
+    }
+}
+
+pub(crate) struct ClientCounts {
+    clients: LruCache<IpAddr, DecayingCount>,
+    pub(crate) window: Duration,
+}
+
+impl ClientCounts {
+    pub(crate) fn new(window: Duration) -> Self {
+        ClientCounts {
+            clients: LruCache::new(
+                NonZeroUsize::new(MAX_COUNTED_CLIENTS).unwrap_or(NonZeroUsize::MIN),
+            ),
+            window,
+        }
+    }
+
+    pub(crate) fn record(&mut self, client: IpAddr, now: Instant) {
+        let window = self.window;
+        let entry = self.clients.get_or_insert_mut(client, || DecayingCount {
+            count: 0.0,
+            updated_at: now,
+        });
+        entry.count = entry.at(now, window) + 1.0;
+        entry.updated_at = now;
+    }
+
+    /// The `n` clients with the highest recent counts, highest first.
+    pub(crate) fn top(&self, n: usize, now: Instant) -> Vec<(IpAddr, f64)> {
+        let mut counts: Vec<(IpAddr, f64)> = self
+            .clients
+            .iter()
+            .map(|(&ip, count)| (ip, count.at(now, self.window)))
+            .collect();
+        counts.sort_by(|a, b| b.1.total_cmp(&a.1));
+        counts.truncate(n);
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn the_busiest_clients_come_first_and_fade_out() {
+        let mut counts = ClientCounts::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let (busy, quiet) = (
+            IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::from(Ipv4Addr::new(10, 0, 0, 2)),
+        );
+        for _ in 0..10 {
+            counts.record(busy, start);
+        }
+        counts.record(quiet, start);
+
+        let top = counts.top(5, start);
+        assert_eq!(
+            top.iter().map(|&(ip, _)| ip).collect::<Vec<_>>(),
+            [busy, quiet]
+        );
+        assert!((top[0].1 - 10.0).abs() < 1e-9);
+        assert_eq!(counts.top(1, start).len(), 1);
+
+        // A window later the counts are down to about a third.
+        let later = counts.top(1, start + Duration::from_secs(60));
+        assert!(
+            (later[0].1 - 10.0 / std::f64::consts::E).abs() < 1e-9,
+            "{:?}",
+            later
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! HTTP clients for talking to the backends, over plain TCP or TLS.
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::Duration;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, Error as TlsError, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+
+use crate::config::{BackendProtocol, LbConfig};
+use crate::load_cert_and_key;
+
+/// Client for backend URLs, speaking TLS to `https://` ones and plain TCP otherwise.
+pub(crate) type UpstreamClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// Pooled upstream clients, one per backend protocol, shared by forwarding and health checks
+/// so connections get reused, plus one for the metrics scrapes.
+pub(crate) struct UpstreamClients {
+    pub(crate) http1: UpstreamClient,
+    h2c: UpstreamClient,
+    /// HTTP/1.1 with `metrics_connect_timeout_ms` as its connect timeout.
+    pub(crate) metrics: UpstreamClient,
+}
+
+impl UpstreamClients {
+    pub(crate) fn new(config: &LbConfig) -> Result<Self, String> {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(Duration::from_secs(config.connect_timeout_secs)));
+        // Let `https://` URIs through to the TLS layer.
+        connector.enforce_http(false);
+        let tls = upstream_tls_config(config)?;
+        let mut builder = Client::builder();
+        builder.pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs));
+        if let Some(max_idle) = config.pool_max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+        // Each client offers only its own HTTP version in ALPN, so TLS backends can't switch it.
+        let http1 = HttpsConnectorBuilder::new()
+            .with_tls_config(tls.clone())
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(connector.clone());
+        let mut metrics_connector = connector.clone();
+        metrics_connector.set_connect_timeout(Some(Duration::from_millis(
+            config.metrics_connect_timeout_ms,
+        )));
+        let metrics = HttpsConnectorBuilder::new()
+            .with_tls_config(tls.clone())
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(metrics_connector);
+        let h2 = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http2()
+            .wrap_connector(connector);
+        Ok(UpstreamClients {
+            http1: builder.build(http1),
+            metrics: builder.build(metrics),
+            h2c: builder.http2_only(true).build(h2),
+        })
+    }
+
+    pub(crate) fn get(&self, protocol: BackendProtocol) -> &UpstreamClient {
+        match protocol {
+            BackendProtocol::Http1 => &self.http1,
+            BackendProtocol::H2c => &self.h2c,
+        }
+    }
+}
+
+/// Client TLS settings for `https://` backends: verified against the public web roots, or
+/// against `upstream_ca_path` only when set, or not at all with `upstream_tls_skip_verify`.
+/// Backends asking for a client certificate get `upstream_client_cert_path`, if there is one.
+fn upstream_tls_config(config: &LbConfig) -> Result<ClientConfig, String> {
+    let client_cert = match (
+        &config.upstream_client_cert_path,
+        &config.upstream_client_key_path,
+    ) {
+        (Some(cert_path), Some(key_path)) => Some(load_cert_and_key(cert_path, key_path)?),
+        _ => None,
+    };
+    let invalid = |e: TlsError| format!("invalid client certificate or key: {}", e);
+    let builder = ClientConfig::builder().with_safe_defaults();
+    if config.upstream_tls_skip_verify {
+        let builder = builder.with_custom_certificate_verifier(Arc::new(SkipServerVerification));
+        return match client_cert {
+            Some((certs, key)) => builder.with_client_auth_cert(certs, key).map_err(invalid),
+            None => Ok(builder.with_no_client_auth()),
+        };
+    }
+    let builder = builder.with_root_certificates(upstream_roots(config)?);
+    match client_cert {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key).map_err(invalid),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn upstream_roots(config: &LbConfig) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    match &config.upstream_ca_path {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .map_err(|e| format!("failed to read certificates from {}: {}", path, e))?;
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(format!("no certificates found in {}", path));
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        })),
+    }
+    Ok(roots)
+}
+
+/// Accepts whatever certificate a backend presents; the handshake signatures are still checked.
+struct SkipServerVerification;
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,1445 @@
+//! Starts the load balancer against mock backends on ephemeral ports and checks where
+//! requests end up as the backends' KV cache usage changes.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Response, Server, StatusCode};
+use load_balancer::{
+    run, run_with_selector, BackendView, LbConfig, RequestContext, RunningServer, Selector,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Every mock backend reports this many KV cache blocks in total.
+const MAX_BLOCKS: u64 = 100;
+
+/// A backend that answers every request with its own name and serves Triton-style KV cache
+/// gauges on `/metrics`, reporting however many blocks `used` is set to.
+struct MockBackend {
+    addr: SocketAddr,
+    used: Arc<AtomicU64>,
+}
+
+impl MockBackend {
+    fn start(name: &'static str, used: u64) -> Self {
+        let used = Arc::new(AtomicU64::new(used));
+        let blocks = used.clone();
+        let make_svc = make_service_fn(move |_| {
+            let blocks = blocks.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let body = if req.uri().path() == "/metrics" {
+                        format!(
+                            "nv_trt_llm_kv_cache_block_metrics{{kv_cache_block_type=\"used\",model=\"tensorrt_llm\",version=\"1\"}} {}\n\
+                             nv_trt_llm_kv_cache_block_metrics{{kv_cache_block_type=\"max\",model=\"tensorrt_llm\",version=\"1\"}} {}\n",
+                            blocks.load(Ordering::Relaxed),
+                            MAX_BLOCKS
+                        )
+                    } else {
+                        name.to_string()
+                    };
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        MockBackend { addr, used }
+    }
+
+    fn set_used(&self, used: u64) {
+        self.used.store(used, Ordering::Relaxed);
+    }
+
+    fn config(&self, name: &str) -> String {
+        format!(
+            "[[backends]]\nname = \"{name}\"\nbase_uri = \"http://{addr}\"\nkv_metrics_url = \"http://{addr}/metrics\"\n",
+            name = name,
+            addr = self.addr
+        )
+    }
+}
+
+fn lb_config(settings: &str, backends: &[(&str, &MockBackend)]) -> LbConfig {
+    let mut text = format!(
+        "listen_addr = \"127.0.0.1:0\"\nmetrics_poll_interval_secs = 1\n{}\n",
+        settings
+    );
+    for (name, backend) in backends {
+        text.push_str(&backend.config(name));
+    }
+    LbConfig::from_toml(&text).expect("valid test config")
+}
+
+async fn start_lb(settings: &str, backends: &[(&str, &MockBackend)]) -> RunningServer {
+    run(lb_config(settings, backends))
+        .await
+        .expect("load balancer starts")
+}
+
+async fn get(lb: &RunningServer, path: &str) -> (StatusCode, String) {
+    let uri = format!("http://{}{}", lb.local_addr(), path);
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("load balancer answers");
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body())
+        .await
+        .expect("complete body");
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Sends requests until one is answered by `backend`, failing after a few poll intervals.
+async fn wait_for_backend(lb: &RunningServer, backend: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (status, body) = get(lb, "/v2/models/ensemble/generate").await;
+        if status == StatusCode::OK && body == backend {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "requests never reached {}, last got {} {:?}",
+            backend,
+            status,
+            body
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn threshold_spills_from_primary_and_returns() {
+    let primary = MockBackend::start("primary", 10);
+    let spare = MockBackend::start("spare", 30);
+    let lb = start_lb("", &[("primary", &primary), ("spare", &spare)]).await;
+
+    wait_for_backend(&lb, "primary").await;
+    for _ in 0..5 {
+        assert_eq!(get(&lb, "/").await.1, "primary");
+    }
+
+    // Above the default capacity threshold of 0.7.
+    primary.set_used(90);
+    wait_for_backend(&lb, "spare").await;
+
+    // Between the thresholds the primary keeps shedding, then takes traffic again once it
+    // drops below the release threshold of 0.6.
+    primary.set_used(65);
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(get(&lb, "/").await.1, "spare");
+    primary.set_used(20);
+    wait_for_backend(&lb, "primary").await;
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn least_loaded_follows_the_emptier_cache() {
+    let a = MockBackend::start("a", 20);
+    let b = MockBackend::start("b", 80);
+    let lb = start_lb(
+        "routing_strategy = \"least_loaded\"",
+        &[("a", &a), ("b", &b)],
+    )
+    .await;
+
+    wait_for_backend(&lb, "a").await;
+    a.set_used(85);
+    b.set_used(15);
+    // Scrapes are staggered, so give both backends a full poll interval to be scraped again.
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+    wait_for_backend(&lb, "b").await;
+    for _ in 0..5 {
+        assert_eq!(get(&lb, "/").await.1, "b");
+    }
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn unreachable_backends_are_routed_around() {
+    let up = MockBackend::start("up", 95);
+    // Nothing listens here once the listener is dropped.
+    let down_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let down = MockBackend {
+        addr: down_addr,
+        used: Arc::new(AtomicU64::new(0)),
+    };
+    let lb = start_lb("max_retries = 0", &[("down", &down), ("up", &up)]).await;
+
+    // The offline primary is skipped even though the other backend is nearly full.
+    wait_for_backend(&lb, "up").await;
+    for _ in 0..5 {
+        assert_eq!(get(&lb, "/").await.1, "up");
+    }
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn no_online_backend_answers_503_with_retry_after() {
+    // Nothing listens on either once the listeners are dropped.
+    let down = || {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        MockBackend {
+            addr,
+            used: Arc::new(AtomicU64::new(0)),
+        }
+    };
+    let (a, b) = (down(), down());
+    let lb = start_lb("unavailable_retry_after_secs = 7", &[("a", &a), ("b", &b)]).await;
+
+    // Both go offline with their first failed scrape.
+    let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let resp = loop {
+        let resp = Client::new()
+            .get(uri.parse().unwrap())
+            .await
+            .expect("load balancer answers");
+        if resp.status() == StatusCode::SERVICE_UNAVAILABLE {
+            break resp;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "backends never went offline, last got {}",
+            resp.status()
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    assert_eq!(resp.headers()["retry-after"], "7");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).expect("a JSON error");
+    assert_eq!(body["error"], "no_backend_available");
+
+    lb.shutdown().await;
+}
+
+/// Sends everything to the fullest cache, the opposite of `least_loaded`.
+struct Busiest;
+
+impl Selector for Busiest {
+    fn name(&self) -> &'static str {
+        "busiest"
+    }
+
+    fn select(&self, backends: &[BackendView<'_>], _req: &RequestContext<'_>) -> Option<usize> {
+        (0..backends.len())
+            .filter(|&i| backends[i].available)
+            .max_by(|&x, &y| backends[x].pressure.total_cmp(&backends[y].pressure))
+    }
+}
+
+#[tokio::test]
+async fn custom_selectors_replace_the_strategy() {
+    let a = MockBackend::start("a", 20);
+    let b = MockBackend::start("b", 80);
+    let config = lb_config(
+        "routing_strategy = \"least_loaded\"",
+        &[("a", &a), ("b", &b)],
+    );
+    let lb = run_with_selector(config, Arc::new(Busiest))
+        .await
+        .expect("load balancer starts");
+
+    wait_for_backend(&lb, "b").await;
+    a.set_used(85);
+    b.set_used(15);
+    wait_for_backend(&lb, "a").await;
+    for _ in 0..5 {
+        assert_eq!(get(&lb, "/").await.1, "a");
+    }
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn unreachable_metrics_endpoints_count_as_connect_errors() {
+    let up = MockBackend::start("up", 10);
+    // Nothing listens here once the listener is dropped.
+    let down_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let down = MockBackend {
+        addr: down_addr,
+        used: Arc::new(AtomicU64::new(0)),
+    };
+    let lb = start_lb(
+        "metrics_connect_timeout_ms = 200",
+        &[("up", &up), ("down", &down)],
+    )
+    .await;
+
+    wait_for_backend(&lb, "up").await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (_, metrics) = get(&lb, "/metrics").await;
+        if metrics.contains("lb_metrics_scrape_errors_total{backend=\"down\",reason=\"connect\"}") {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "no connect error counted:\n{}",
+            metrics
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn hanging_metrics_endpoints_time_out() {
+    let up = MockBackend::start("up", 10);
+    // Connections are queued in the backlog but never answered.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let hung = MockBackend {
+        addr: listener.local_addr().unwrap(),
+        used: Arc::new(AtomicU64::new(0)),
+    };
+    // `up` stays primary so no request is sent to `hung` before its scrape fails.
+    let lb = start_lb("metrics_timeout_secs = 1", &[("up", &up), ("hung", &hung)]).await;
+
+    wait_for_backend(&lb, "up").await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (_, metrics) = get(&lb, "/metrics").await;
+        if metrics.contains("lb_metrics_scrape_errors_total{backend=\"hung\",reason=\"timeout\"}") {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "no scrape timeout counted:\n{}",
+            metrics
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    drop(listener);
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn the_request_deadline_cuts_attempts_short() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let hung = MockBackend {
+        addr: listener.local_addr().unwrap(),
+        used: Arc::new(AtomicU64::new(0)),
+    };
+    let lb = start_lb("request_deadline_secs = 1", &[("hung", &hung)]).await;
+
+    let started = Instant::now();
+    let (status, body) = get(&lb, "/").await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "request_deadline_exceeded");
+    assert_eq!(body["attempts"], 1);
+    assert!(body["message"].is_string(), "no message in {}", body);
+    assert!(body["request_id"].is_string(), "no request ID in {}", body);
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "took {:?}",
+        started.elapsed()
+    );
+
+    drop(listener);
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn pushed_kv_stats_override_polling() {
+    let a = MockBackend::start("a", 80);
+    let b = MockBackend::start("b", 20);
+    let lb = start_lb("admin_token = \"secret\"", &[("a", &a), ("b", &b)]).await;
+    wait_for_backend(&lb, "b").await;
+
+    let push = |token: &'static str, backend: &str, body: &'static str| {
+        let uri = format!("http://{}/admin/metrics/{}", lb.local_addr(), backend);
+        let req = hyper::Request::post(uri)
+            .header("x-admin-token", token)
+            .body(Body::from(body))
+            .unwrap();
+        async move {
+            Client::new()
+                .request(req)
+                .await
+                .expect("load balancer answers")
+                .status()
+        }
+    };
+    assert_eq!(
+        push("wrong", "a", r#"{"used":10,"max":100}"#).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        push("secret", "c", r#"{"used":10,"max":100}"#).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        push("secret", "a", r#"{"used":200,"max":100}"#).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        push("secret", "a", r#"{"used":10,"max":100}"#).await,
+        StatusCode::OK
+    );
+
+    // The primary is back under its threshold at once, and its scrapes (still 80%) are ignored.
+    assert_eq!(get(&lb, "/").await.1, "a");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(get(&lb, "/").await.1, "a");
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn discovered_backends_join_and_leave() {
+    let configured = MockBackend::start("configured", 90);
+    let found = MockBackend::start("found", 10);
+    let list = std::env::temp_dir().join(format!("lb-discovery-{}.json", std::process::id()));
+    let entry = format!(
+        r#"[{{"name":"found","base_uri":"http://{addr}","kv_metrics_url":"http://{addr}/metrics"}}]"#,
+        addr = found.addr
+    );
+    std::fs::write(&list, entry).unwrap();
+    let settings = format!(
+        "discovery_source = {:?}\ndiscovery_interval_secs = 1",
+        list.display().to_string()
+    );
+    let lb = start_lb(&settings, &[("configured", &configured)]).await;
+
+    // The configured backend is over its threshold, so the discovered one takes the traffic.
+    wait_for_backend(&lb, "found").await;
+    std::fs::write(&list, "[]").unwrap();
+    wait_for_backend(&lb, "configured").await;
+
+    std::fs::remove_file(&list).unwrap();
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn connections_past_max_connections_are_closed() {
+    let backend = MockBackend::start("backend", 10);
+    let settings =
+        "max_connections = 1\nadmin_listener = true\nadmin_listen_addr = \"127.0.0.1:0\"";
+    let lb = start_lb(settings, &[("backend", &backend)]).await;
+    let admin = lb.admin_addr().expect("admin listener is bound");
+
+    let held = tokio::net::TcpStream::connect(lb.local_addr())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let uri = format!("http://{}/admin/connections", admin);
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("admin listener answers");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], br#"{"active":1,"max_connections":1}"#);
+    let uri = format!("http://{}/", lb.local_addr());
+    assert!(Client::new().get(uri.parse().unwrap()).await.is_err());
+
+    drop(held);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(get(&lb, "/").await, (StatusCode::OK, "backend".to_string()));
+
+    lb.shutdown().await;
+}
+
+/// A backend without metrics that answers `ok` and counts the connections it accepts.
+fn start_counting_backend() -> (SocketAddr, Arc<AtomicU64>) {
+    let connections = Arc::new(AtomicU64::new(0));
+    let accepted = connections.clone();
+    let make_svc = make_service_fn(move |_| {
+        accepted.fetch_add(1, Ordering::Relaxed);
+        async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, connections)
+}
+
+#[tokio::test]
+async fn sequential_requests_share_one_upstream_connection() {
+    let (backend, connections) = start_counting_backend();
+    let settings = format!(
+        "[[backends]]\nname = \"counting\"\nbase_uri = \"http://{}\"\n",
+        backend
+    );
+    let lb = start_lb(&settings, &[]).await;
+
+    for _ in 0..20 {
+        assert_eq!(
+            get(&lb, "/v2/models/ensemble/generate").await,
+            (StatusCode::OK, "ok".to_string())
+        );
+    }
+    assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn pool_settings_decide_when_connections_are_reused() {
+    let (backend, connections) = start_counting_backend();
+    let backends = format!(
+        "[[backends]]\nname = \"counting\"\nbase_uri = \"http://{}\"\n",
+        backend
+    );
+
+    // Nothing is kept idle, so every request needs a connection of its own.
+    let lb = start_lb(&format!("pool_max_idle_per_host = 0\n{}", backends), &[]).await;
+    for _ in 0..10 {
+        assert_eq!(get(&lb, "/").await.0, StatusCode::OK);
+    }
+    assert_eq!(connections.load(Ordering::Relaxed), 10);
+    lb.shutdown().await;
+
+    // Reused while it is fresh, replaced once it has been idle for longer than the timeout.
+    connections.store(0, Ordering::Relaxed);
+    let lb = start_lb(&format!("pool_idle_timeout_secs = 1\n{}", backends), &[]).await;
+    for _ in 0..10 {
+        assert_eq!(get(&lb, "/").await.0, StatusCode::OK);
+    }
+    assert_eq!(connections.load(Ordering::Relaxed), 1);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(get(&lb, "/").await.0, StatusCode::OK);
+    assert_eq!(connections.load(Ordering::Relaxed), 2);
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn client_disconnects_cancel_the_upstream_request() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A backend that takes requests but never answers, telling when it got one and when the
+    // load balancer hung up on it.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap();
+    let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel::<&'static str>();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 4096];
+        let mut seen = Vec::new();
+        loop {
+            let n = conn.read(&mut buf).await.unwrap_or(0);
+            if n == 0 {
+                let _ = events_tx.send("closed");
+                return;
+            }
+            seen.extend_from_slice(&buf[..n]);
+            if seen.windows(4).any(|w| w == b"\r\n\r\n") {
+                let _ = events_tx.send("received");
+                seen.clear();
+            }
+        }
+    });
+    let settings = format!(
+        "admin_listener = true\nadmin_listen_addr = \"127.0.0.1:0\"\n[[backends]]\nname = \"silent\"\nbase_uri = \"http://{}\"\n",
+        backend
+    );
+    let lb = start_lb(&settings, &[]).await;
+    let admin = lb.admin_addr().expect("admin listener is bound");
+
+    let mut client = tokio::net::TcpStream::connect(lb.local_addr())
+        .await
+        .unwrap();
+    client
+        .write_all(b"GET /v2/models/ensemble/generate HTTP/1.1\r\nhost: lb\r\n\r\n")
+        .await
+        .unwrap();
+    let wait = Duration::from_secs(5);
+    assert_eq!(
+        tokio::time::timeout(wait, events.recv()).await,
+        Ok(Some("received"))
+    );
+    drop(client);
+    // Well within `backend_timeout_secs`, so it's the disconnect that ends the forward.
+    assert_eq!(
+        tokio::time::timeout(wait, events.recv()).await,
+        Ok(Some("closed"))
+    );
+
+    let uri = format!("http://{}/admin/backends", admin);
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("admin listener answers");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["backends"][0]["in_flight"], 0, "{}", body);
+
+    lb.shutdown().await;
+}
+
+/// A backend that accepts connections and reads requests but never answers them.
+async fn start_silent_backend() -> SocketAddr {
+    use tokio::io::AsyncReadExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = vec![0; 4096];
+                while conn.read(&mut buf).await.unwrap_or(0) > 0 {}
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn first_byte_timeouts_fail_over() {
+    let silent = start_silent_backend().await;
+    let up = MockBackend::start("up", 10);
+    let settings = format!(
+        "max_retries = 1\nfirst_byte_timeout_secs = 1\n[[backends]]\nname = \"silent\"\nbase_uri = \"http://{}\"\n",
+        silent
+    );
+    let lb = start_lb(&settings, &[("up", &up)]).await;
+
+    let started = Instant::now();
+    assert_eq!(
+        get(&lb, "/v2/models/ensemble/generate").await,
+        (StatusCode::OK, "up".to_string())
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "took {:?}",
+        started.elapsed()
+    );
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn response_timeouts_cut_off_streaming_bodies() {
+    // A backend that sends the start of its body and then nothing more.
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data("data: first\n\n".into()).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                drop(sender);
+            });
+            Ok::<_, Infallible>(Response::new(body))
+        }))
+    });
+    let backend = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let settings = format!(
+        "response_timeout_secs = 1\n[[backends]]\nname = \"stalling\"\nbase_uri = \"http://{}\"\n",
+        backend.local_addr()
+    );
+    tokio::spawn(backend);
+    let lb = start_lb(&settings, &[]).await;
+
+    let started = Instant::now();
+    let uri = format!(
+        "http://{}/v2/models/ensemble/generate_stream",
+        lb.local_addr()
+    );
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("load balancer answers");
+    assert_eq!(resp.status(), StatusCode::OK);
+    let mut body = resp.into_body();
+    let first = hyper::body::HttpBody::data(&mut body)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&first[..], b"data: first\n\n");
+    let rest = tokio::time::timeout(Duration::from_secs(5), hyper::body::to_bytes(body))
+        .await
+        .expect("the body is cut off at the response timeout");
+    assert!(rest.is_err(), "the body ended cleanly: {:?}", rest);
+    assert!(
+        started.elapsed() < Duration::from_secs(3),
+        "took {:?}",
+        started.elapsed()
+    );
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn latency_covers_streamed_bodies() {
+    // A backend that sends its headers at once and finishes the body a second later.
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data("data: first\n\n".into()).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                sender.send_data("data: last\n\n".into()).await.unwrap();
+            });
+            Ok::<_, Infallible>(Response::new(body))
+        }))
+    });
+    let backend = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let settings = format!(
+        "admin_listener = true\nadmin_listen_addr = \"127.0.0.1:0\"\n[[backends]]\nname = \"slow\"\nbase_uri = \"http://{}\"\n",
+        backend.local_addr()
+    );
+    tokio::spawn(backend);
+    let lb = start_lb(&settings, &[]).await;
+    let admin = lb.admin_addr().expect("admin listener is bound");
+
+    let (status, body) = get(&lb, "/v2/models/ensemble/generate_stream").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "data: first\n\ndata: last\n\n");
+
+    let uri = format!("http://{}/admin/backends", admin);
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("admin listener answers");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let p50 = body["backends"][0]["latency_ms"]["p50"]
+        .as_f64()
+        .expect("the response is measured");
+    assert!(p50 >= 1000.0, "{}", body);
+
+    lb.shutdown().await;
+}
+
+/// A backend that answers every request with a 503 asking to come back in 7 seconds.
+fn start_unavailable_backend() -> SocketAddr {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            let resp = Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("retry-after", "7")
+                .body(Body::from("overloaded"))
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn retryable_statuses_fail_over() {
+    // Neither is scraped, so both take requests at once and the first one hits the 503.
+    let up = MockBackend::start("up", 0);
+    let settings = format!(
+        "max_retries = 1\nretry_on_status = [503]\n[[backends]]\nname = \"unavailable\"\nbase_uri = \"http://{}\"\n[[backends]]\nname = \"up\"\nbase_uri = \"http://{}\"\n",
+        start_unavailable_backend(),
+        up.addr
+    );
+    let lb = start_lb(&settings, &[]).await;
+
+    let (status, body) = get(&lb, "/v2/models/ensemble/generate").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "up");
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn retryable_statuses_are_returned_with_no_backend_left() {
+    let settings = format!(
+        "max_retries = 1\nretry_on_status = [503]\n[[backends]]\nname = \"unavailable\"\nbase_uri = \"http://{}\"\n",
+        start_unavailable_backend()
+    );
+    let lb = start_lb(&settings, &[]).await;
+
+    let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("load balancer answers");
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers()["retry-after"], "7");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"overloaded");
+
+    lb.shutdown().await;
+}
+
+/// A backend that answers with the framing of the request it got:
+/// `<content-length> <transfer-encoding> <body length>`, with `-` for a missing header.
+fn start_framing_backend() -> SocketAddr {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .map_or("-".to_string(), |v| v.to_str().unwrap().to_string())
+            };
+            let (content_length, transfer_encoding) =
+                (header("content-length"), header("transfer-encoding"));
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let framing = format!("{} {} {}", content_length, transfer_encoding, body.len());
+            Ok::<_, Infallible>(Response::new(Body::from(framing)))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn forwarded_bodies_are_framed_afresh() {
+    let backend = start_framing_backend();
+    for max_retries in [0, 1] {
+        let settings = format!(
+            "max_retries = {}\n[[backends]]\nname = \"framing\"\nbase_uri = \"http://{}\"\n",
+            max_retries, backend
+        );
+        let lb = start_lb(&settings, &[]).await;
+        let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+        let send = |body: Body| {
+            let req = hyper::Request::post(uri.as_str()).body(body).unwrap();
+            async move {
+                let resp = Client::new()
+                    .request(req)
+                    .await
+                    .expect("load balancer answers");
+                String::from_utf8(
+                    hyper::body::to_bytes(resp.into_body())
+                        .await
+                        .unwrap()
+                        .to_vec(),
+                )
+                .unwrap()
+            }
+        };
+
+        assert_eq!(send(Body::from("0123456789")).await, "10 - 10");
+        let chunks = futures_util::stream::iter(["01234", "56789"].map(Ok::<_, std::io::Error>));
+        // Buffered for retries, a chunked body gets a Content-Length; streamed, it stays chunked.
+        let chunked = if max_retries > 0 {
+            "10 - 10"
+        } else {
+            "- chunked 10"
+        };
+        assert_eq!(send(Body::wrap_stream(chunks)).await, chunked);
+        assert_eq!(send(Body::empty()).await, "- - 0");
+
+        lb.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn oversized_bodies_get_413_streamed_or_not() {
+    let backend = start_framing_backend();
+    let settings = format!(
+        "max_body_bytes = 1024\n[[backends]]\nname = \"framing\"\nbase_uri = \"http://{}\"\n",
+        backend
+    );
+    let lb = start_lb(&settings, &[]).await;
+    let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+    let send = |body: Body| {
+        let req = hyper::Request::post(uri.as_str()).body(body).unwrap();
+        async move {
+            Client::new()
+                .request(req)
+                .await
+                .expect("load balancer answers")
+                .status()
+        }
+    };
+
+    assert_eq!(
+        send(Body::from(vec![b'x'; 2048])).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    // Chunked, so only found out while streaming it to the backend.
+    let chunks =
+        futures_util::stream::iter((0..4).map(|_| Ok::<_, std::io::Error>(vec![b'x'; 512])));
+    assert_eq!(
+        send(Body::wrap_stream(chunks)).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    let chunks =
+        futures_util::stream::iter((0..2).map(|_| Ok::<_, std::io::Error>(vec![b'x'; 512])));
+    assert_eq!(send(Body::wrap_stream(chunks)).await, StatusCode::OK);
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn large_bodies_stream_through_without_being_held() {
+    const CHUNK: usize = 512 * 1024;
+    const CHUNKS: usize = 8;
+    // A backend that reports how much of the body it has received after every frame.
+    let (progress_tx, mut progress) = tokio::sync::mpsc::unbounded_channel::<usize>();
+    let make_svc = make_service_fn(move |_| {
+        let progress_tx = progress_tx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                let progress_tx = progress_tx.clone();
+                async move {
+                    let mut body = req.into_body();
+                    let mut received = 0;
+                    while let Some(data) = hyper::body::HttpBody::data(&mut body).await {
+                        received += data?.len();
+                        let _ = progress_tx.send(received);
+                    }
+                    Ok::<_, hyper::Error>(Response::new(Body::from(received.to_string())))
+                }
+            }))
+        }
+    });
+    let backend = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let settings = format!(
+        "[[backends]]\nname = \"sink\"\nbase_uri = \"http://{}\"\n",
+        backend.local_addr()
+    );
+    tokio::spawn(backend);
+    let lb = start_lb(&settings, &[]).await;
+
+    let (mut sender, body) = Body::channel();
+    let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+    let req = hyper::Request::post(uri).body(body).unwrap();
+    let resp = tokio::spawn(Client::new().request(req));
+    // Each chunk is only sent once the backend has everything before it, so a load balancer
+    // holding the body back until it is complete would never see the end of it.
+    for sent in 1..=CHUNKS {
+        sender.send_data(vec![b'x'; CHUNK].into()).await.unwrap();
+        let wait =
+            async { while progress.recv().await.expect("backend is running") < sent * CHUNK {} };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap_or_else(|_| panic!("chunk {} never reached the backend", sent));
+    }
+    drop(sender);
+    let resp = resp.await.unwrap().expect("load balancer answers");
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&body), (CHUNK * CHUNKS).to_string());
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn event_streams_are_relayed_chunk_by_chunk() {
+    // A backend that sends the first event right away and the last one only when told to.
+    let (release_tx, release) = tokio::sync::oneshot::channel::<()>();
+    let release = Arc::new(tokio::sync::Mutex::new(Some(release)));
+    let make_svc = make_service_fn(move |_| {
+        let release = release.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_| {
+                let release = release.clone();
+                async move {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        sender.send_data("data: first\n\n".into()).await.unwrap();
+                        if let Some(release) = release.lock().await.take() {
+                            let _ = release.await;
+                        }
+                        sender.send_data("data: last\n\n".into()).await.unwrap();
+                    });
+                    let resp = Response::builder()
+                        .header("content-type", "text/event-stream")
+                        .body(body)
+                        .unwrap();
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+    let backend = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let settings = format!(
+        "[[backends]]\nname = \"sse\"\nbase_uri = \"http://{}\"\n",
+        backend.local_addr()
+    );
+    tokio::spawn(backend);
+    let lb = start_lb(&settings, &[]).await;
+
+    let uri = format!(
+        "http://{}/v2/models/ensemble/generate_stream",
+        lb.local_addr()
+    );
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("load balancer answers");
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    let mut body = resp.into_body();
+    let first = tokio::time::timeout(
+        Duration::from_secs(5),
+        hyper::body::HttpBody::data(&mut body),
+    )
+    .await
+    .expect("the first event arrives while the backend is still streaming")
+    .unwrap()
+    .unwrap();
+    assert_eq!(&first[..], b"data: first\n\n");
+
+    release_tx.send(()).unwrap();
+    let rest = hyper::body::to_bytes(body).await.unwrap();
+    assert_eq!(&rest[..], b"data: last\n\n");
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn admin_reload_applies_the_config_file() {
+    let backend = MockBackend::start("backend", 10);
+    let path = std::env::temp_dir().join(format!("lb-reload-{}.toml", std::process::id()));
+    let text = |threshold: &str| {
+        format!(
+            "listen_addr = \"127.0.0.1:0\"\nadmin_token = \"secret\"\ncapacity_threshold = {}\n{}",
+            threshold,
+            backend.config("backend")
+        )
+    };
+    std::fs::write(&path, text("0.7")).unwrap();
+    // Only reloads read LB_CONFIG, and this is the one test that reloads.
+    std::env::set_var("LB_CONFIG", &path);
+    let lb = run(LbConfig::from_toml(&text("0.7")).unwrap())
+        .await
+        .expect("load balancer starts");
+    let reload = || {
+        let uri = format!("http://{}/admin/reload", lb.local_addr());
+        let req = hyper::Request::post(uri)
+            .header("x-admin-token", "secret")
+            .body(Body::empty())
+            .unwrap();
+        async move {
+            let resp = Client::new()
+                .request(req)
+                .await
+                .expect("load balancer answers");
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    std::fs::write(&path, text("0.8")).unwrap();
+    let (status, summary) = reload().await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        summary["changed"],
+        serde_json::json!({ "capacity_threshold": { "old": 0.7, "new": 0.8 } })
+    );
+    assert_eq!(summary["restart_required"], serde_json::json!([]));
+
+    std::fs::write(&path, text("1.5")).unwrap();
+    let (status, error) = reload().await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["error"], "invalid_config");
+    assert!(
+        error["message"]
+            .as_str()
+            .unwrap()
+            .contains("capacity_threshold"),
+        "{}",
+        error
+    );
+
+    std::fs::remove_file(&path).unwrap();
+    lb.shutdown().await;
+}
+
+/// A backend that accepts WebSocket handshakes and then echoes whatever it receives.
+fn start_echo_backend() -> SocketAddr {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|mut req: hyper::Request<Body>| async move {
+            let upgrade = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                if let Ok(upgraded) = upgrade.await {
+                    let (mut read, mut write) = tokio::io::split(upgraded);
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                }
+            });
+            let resp = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .body(Body::empty())
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn websocket_upgrades_are_relayed() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let backend = start_echo_backend();
+    let settings = format!(
+        "[[backends]]\nname = \"echo\"\nbase_uri = \"http://{}\"\n",
+        backend
+    );
+    let lb = start_lb(&settings, &[]).await;
+
+    let req = hyper::Request::get(format!("http://{}/ws", lb.local_addr()))
+        .header("connection", "Upgrade")
+        .header("upgrade", "websocket")
+        .body(Body::empty())
+        .unwrap();
+    let resp = Client::new()
+        .request(req)
+        .await
+        .expect("load balancer answers");
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    let mut upgraded = hyper::upgrade::on(resp).await.expect("connection upgraded");
+    upgraded.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    upgraded.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    drop(upgraded);
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn the_default_backend_serves_until_the_first_scrape() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let unscraped = MockBackend {
+        addr: listener.local_addr().unwrap(),
+        used: Arc::new(AtomicU64::new(0)),
+    };
+    let spare = MockBackend::start("spare", 10);
+    // Without a default the primary would get requests at once, and hang them.
+    let lb = start_lb(
+        "default_backend = \"spare\"",
+        &[("unscraped", &unscraped), ("spare", &spare)],
+    )
+    .await;
+
+    let started = Instant::now();
+    assert_eq!(get(&lb, "/").await, (StatusCode::OK, "spare".to_string()));
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "took {:?}",
+        started.elapsed()
+    );
+
+    drop(listener);
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn the_model_in_the_body_picks_the_pool() {
+    let big = MockBackend::start("big", 10);
+    let small = MockBackend::start("small", 10);
+    let settings = format!(
+        "model_pointer = \"/model\"\nmodel_pools = {{ tiny = \"small\" }}\n{}pool = \"small\"\n",
+        small.config("small")
+    );
+    let lb = start_lb(&settings, &[("big", &big)]).await;
+
+    let post = |body: &'static str| {
+        let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+        async move {
+            let req = hyper::Request::post(uri).body(Body::from(body)).unwrap();
+            let resp = Client::new()
+                .request(req)
+                .await
+                .expect("load balancer answers");
+            let body = hyper::body::to_bytes(resp.into_body())
+                .await
+                .expect("complete body");
+            String::from_utf8_lossy(&body).into_owned()
+        }
+    };
+    assert_eq!(post(r#"{"model":"tiny","text_input":"hi"}"#).await, "small");
+    // Unlisted models, and bodies without one, go by the path as usual.
+    assert_eq!(post(r#"{"model":"huge","text_input":"hi"}"#).await, "big");
+    assert_eq!(post(r#"{"text_input":"hi"}"#).await, "big");
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn injected_failures_fail_over_until_they_expire() {
+    let primary = MockBackend::start("primary", 10);
+    let spare = MockBackend::start("spare", 10);
+    let settings =
+        "chaos_enabled = true\nadmin_token = \"secret\"\nmax_retries = 1\nretry_on_status = [503]\nbreaker_cooldown_secs = 1";
+    let lb = start_lb(settings, &[("primary", &primary), ("spare", &spare)]).await;
+    wait_for_backend(&lb, "primary").await;
+
+    let uri = format!(
+        "http://{}/admin/backends/primary/inject_failure",
+        lb.local_addr()
+    );
+    let req = hyper::Request::post(uri)
+        .header("x-admin-token", "secret")
+        .body(Body::from(r#"{"duration_secs":1}"#))
+        .unwrap();
+    let resp = Client::new()
+        .request(req)
+        .await
+        .expect("load balancer answers");
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The primary never sees these; each fails there and moves on to the spare, until the
+    // failures open its breaker.
+    for _ in 0..5 {
+        assert_eq!(get(&lb, "/").await, (StatusCode::OK, "spare".to_string()));
+    }
+    // Past both the injection and the breaker's cooldown.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    wait_for_backend(&lb, "primary").await;
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn an_unreadable_upstream_client_cert_stops_startup() {
+    let backend = MockBackend::start("backend", 10);
+    let missing =
+        std::env::temp_dir().join(format!("lb-missing-client-{}.pem", std::process::id()));
+    let settings = format!(
+        "upstream_client_cert_path = {:?}\nupstream_client_key_path = {:?}",
+        missing.display().to_string(),
+        missing.display().to_string()
+    );
+    match run(lb_config(&settings, &[("backend", &backend)])).await {
+        Ok(_) => panic!("started without its client certificate"),
+        Err(e) => assert!(e.contains("lb-missing-client"), "{}", e),
+    }
+}
+
+#[tokio::test]
+async fn responses_name_the_backend_that_served_them() {
+    let primary = MockBackend::start("primary", 10);
+    let spare = MockBackend::start("spare", 90);
+    let lb = start_lb(
+        "served_by_header = \"X-Served-By\"",
+        &[("primary", &primary), ("spare", &spare)],
+    )
+    .await;
+    wait_for_backend(&lb, "primary").await;
+
+    let uri = format!("http://{}/", lb.local_addr());
+    let resp = Client::new()
+        .get(uri.parse().unwrap())
+        .await
+        .expect("load balancer answers");
+    assert_eq!(resp.headers()["x-served-by"], "primary");
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn require_backend_on_startup_needs_one_to_answer() {
+    // Nothing listens here once the listener is dropped.
+    let down_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let down = MockBackend {
+        addr: down_addr,
+        used: Arc::new(AtomicU64::new(0)),
+    };
+    match run(lb_config(
+        "require_backend_on_startup = true",
+        &[("down", &down)],
+    ))
+    .await
+    {
+        Ok(_) => panic!("started without a reachable backend"),
+        Err(e) => assert!(e.contains("require_backend_on_startup"), "{}", e),
+    }
+
+    let up = MockBackend::start("up", 10);
+    let lb = start_lb(
+        "require_backend_on_startup = true",
+        &[("down", &down), ("up", &up)],
+    )
+    .await;
+    wait_for_backend(&lb, "up").await;
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn request_trailers_reach_the_backend() {
+    // An HTTP/2 backend answering with the request body and its `x-checksum` trailer.
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+            let mut body = req.into_body();
+            let data = hyper::body::to_bytes(&mut body).await?;
+            let trailers = hyper::body::HttpBody::trailers(&mut body).await?;
+            let checksum = trailers
+                .as_ref()
+                .and_then(|t| t.get("x-checksum"))
+                .and_then(|v| v.to_str().ok());
+            let answer = format!(
+                "{} {}",
+                String::from_utf8_lossy(&data),
+                checksum.unwrap_or("none")
+            );
+            Ok::<_, hyper::Error>(Response::new(Body::from(answer)))
+        }))
+    });
+    let backend = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .http2_only(true)
+        .serve(make_svc);
+    let addr = backend.local_addr();
+    tokio::spawn(backend);
+
+    // Buffered for retries, and streamed through without them.
+    for retries in [1, 0] {
+        let settings = format!(
+            "max_retries = {}\n[[backends]]\nname = \"h2\"\nbase_uri = \"http://{}\"\nprotocol = \"h2c\"\n",
+            retries, addr
+        );
+        let lb = start_lb(&settings, &[]).await;
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data("payload".into()).await.unwrap();
+            let mut trailers = hyper::HeaderMap::new();
+            trailers.insert("x-checksum", "abc123".parse().unwrap());
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        let uri = format!("http://{}/v2/models/ensemble/generate", lb.local_addr());
+        let req = hyper::Request::post(uri).body(body).unwrap();
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let resp = client.request(req).await.expect("load balancer answers");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let answer = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(answer, "payload abc123", "with max_retries = {}", retries);
+        lb.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn host_header_is_kept_or_rewritten_to_the_backend() {
+    // A backend answering with the Host header it got.
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+            let host = req
+                .headers()
+                .get("host")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("none")
+                .to_string();
+            Ok::<_, Infallible>(Response::new(Body::from(host)))
+        }))
+    });
+    let backend = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let addr = backend.local_addr();
+    tokio::spawn(backend);
+
+    for (mode, expected) in [
+        ("preserve", "lb.example"),
+        ("upstream", addr.to_string().as_str()),
+    ] {
+        let settings = format!(
+            "host_header = \"{}\"\n[[backends]]\nname = \"vhost\"\nbase_uri = \"http://{}\"\n",
+            mode, addr
+        );
+        let lb = start_lb(&settings, &[]).await;
+        let uri = format!("http://{}/", lb.local_addr());
+        let req = hyper::Request::get(uri)
+            .header("host", "lb.example")
+            .body(Body::empty())
+            .unwrap();
+        let resp = Client::new()
+            .request(req)
+            .await
+            .expect("load balancer answers");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, expected, "with host_header = {:?}", mode);
+        lb.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn stream_paths_skip_the_buffered_retry() {
+    let up = MockBackend::start("up", 10);
+    // Nothing listens here once the listener is dropped; without a metrics URL it stays online.
+    let down_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let settings = format!(
+        "max_retries = 1\nstream_paths = [\"/v2/health\"]\n[[backends]]\nname = \"down\"\nbase_uri = \"http://{}\"\n",
+        down_addr
+    );
+    let lb = start_lb(&settings, &[("up", &up)]).await;
+
+    // Buffered, so the refused connection is retried on the other backend.
+    assert_eq!(
+        get(&lb, "/v2/models/ensemble/generate").await,
+        (StatusCode::OK, "up".to_string())
+    );
+    // Streamed, so there is nothing to replay.
+    assert_eq!(
+        get(&lb, "/v2/health/ready").await.0,
+        StatusCode::BAD_GATEWAY
+    );
+
+    lb.shutdown().await;
+}
+
+#[tokio::test]
+async fn admin_recommend_names_the_backend_routing_would_pick() {
+    let a = MockBackend::start("a", 30);
+    let b = MockBackend::start("b", 70);
+    let lb = start_lb(
+        "routing_strategy = \"least_loaded\"\nadmin_token = \"secret\"",
+        &[("a", &a), ("b", &b)],
+    )
+    .await;
+    // Scrapes are staggered, so give both backends a full poll interval to be scraped.
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+
+    let recommend = |query: &'static str| {
+        let uri = format!("http://{}/admin/recommend{}", lb.local_addr(), query);
+        let req = hyper::Request::get(uri)
+            .header("x-admin-token", "secret")
+            .body(Body::empty())
+            .unwrap();
+        async move {
+            let resp = Client::new()
+                .request(req)
+                .await
+                .expect("load balancer answers");
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        }
+    };
+    let (status, body) = recommend("").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["backend"], "a");
+    assert_eq!(body["pool"], "default");
+    assert_eq!(body["kv_ratio"], 0.3);
+    assert_eq!(recommend("?pool=nope").await.0, StatusCode::NOT_FOUND);
+
+    lb.shutdown().await;
+}